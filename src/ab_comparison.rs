@@ -0,0 +1,116 @@
+//! A and B slots for comparing two settings of the whole EQ
+
+use serde::{Deserialize, Serialize};
+
+use crate::eq_effect_parameters::EQEffectParameters;
+use crate::state::EQState;
+
+use core::fmt;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum ABSlot {
+    A,
+    B,
+}
+
+impl ABSlot {
+    pub fn other(&self) -> ABSlot {
+        match self {
+            ABSlot::A => ABSlot::B,
+            ABSlot::B => ABSlot::A,
+        }
+    }
+}
+
+impl fmt::Display for ABSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// The active slot is whatever the parameters are set to,
+/// its snapshot is only brought up to date when switching away from it or saving.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ABComparison {
+    pub active: ABSlot,
+    pub a: Option<EQState>,
+    pub b: Option<EQState>,
+}
+
+impl Default for ABComparison {
+    fn default() -> Self {
+        ABComparison {
+            active: ABSlot::A,
+            a: None,
+            b: None,
+        }
+    }
+}
+
+impl ABComparison {
+    fn slot_mut(&mut self, slot: ABSlot) -> &mut Option<EQState> {
+        match slot {
+            ABSlot::A => &mut self.a,
+            ABSlot::B => &mut self.b,
+        }
+    }
+}
+
+impl EQEffectParameters {
+    pub fn active_ab_slot(&self) -> ABSlot {
+        self.ab.lock().map_or(ABSlot::A, |ab| ab.active)
+    }
+
+    /// Snapshot of both slots, with the active one taken from the parameters
+    pub fn get_ab_state(&self) -> ABComparison {
+        let live = self.get_state();
+        match self.ab.lock() {
+            Ok(mut ab) => {
+                let active = ab.active;
+                *ab.slot_mut(active) = Some(live);
+                ab.clone()
+            }
+            Err(_) => ABComparison::default(),
+        }
+    }
+
+    /// Only stores the slots, the parameters are set by the program that is loaded with them
+    pub fn set_ab_state(&self, state: &ABComparison) {
+        if let Ok(mut ab) = self.ab.lock() {
+            *ab = state.clone();
+        }
+    }
+
+    /// Switches to the other slot. A slot that was never used starts as a copy of the current one.
+    pub fn toggle_ab(&self) {
+        let live = self.get_state();
+        let next = match self.ab.lock() {
+            Ok(mut ab) => {
+                let active = ab.active;
+                *ab.slot_mut(active) = Some(live.clone());
+                ab.active = active.other();
+                let other = ab.active;
+                ab.slot_mut(other).clone().unwrap_or(live)
+            }
+            Err(_) => return,
+        };
+        self.set_state(&next);
+    }
+
+    pub fn copy_a_to_b(&self) {
+        let live = self.get_state();
+        let apply = match self.ab.lock() {
+            Ok(mut ab) => match ab.active {
+                ABSlot::A => {
+                    ab.b = Some(live);
+                    None
+                }
+                ABSlot::B => ab.a.clone(),
+            },
+            Err(_) => None,
+        };
+        if let Some(state) = apply {
+            self.set_state(&state);
+        }
+    }
+}
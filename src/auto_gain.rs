@@ -0,0 +1,165 @@
+//! Auto gain, which keeps the EQ about as loud as its input so A/B and bypass
+//! comparisons aren't swayed by level.
+//!
+//! `CurveLoudness` estimates the change from the curve, as the average power gain over
+//! a grid spaced evenly in log frequency, which is how the curve changes pink noise.
+//! Bands on one channel, or only mid or side, are counted as changing half the power.
+//! It runs on the kernel builder thread, which hands the result to the audio thread.
+//! `LiveLoudness` measures the audio before and after the bands with `AccumulatingRMS`.
+
+use audio_filters::{linkwitz_riley::LinkwitzRileyCoefficients, units::ZSample};
+use wide::f64x4;
+
+use crate::eq_effect_parameters::{BandChannel, BandKind};
+use crate::get_coefficients;
+use crate::state::BandState;
+use crate::units::AccumulatingRMS;
+
+/// Points the curve is measured at, from 20 Hz to 20 kHz
+const GRID_SIZE: usize = 48;
+/// Compensation is limited to this many dB either way
+const MAX_COMPENSATION: f64 = 24.0;
+const LIVE_WINDOW_MS: f32 = 1000.0;
+/// Sets the size of the measurement buffers, which are allocated up front
+const LIVE_MAX_SAMPLE_RATE: usize = 192000;
+/// The live measurement holds its last value while either side is quieter than this (-60 dB)
+const LIVE_SILENCE: f32 = 0.001;
+
+fn clamp_compensation(db: f64) -> f64 {
+    db.max(-MAX_COMPENSATION).min(MAX_COMPENSATION)
+}
+
+pub struct CurveLoudness {
+    grid: [f64; GRID_SIZE],
+    /// Power gain of each band at each grid point
+    band_power: Vec<[f64; GRID_SIZE]>,
+    /// Each band and the sample rate its power was calculated for
+    measured: Vec<Option<(BandState, f64)>>,
+    compensation: f64,
+}
+
+impl CurveLoudness {
+    pub fn new(band_count: usize) -> CurveLoudness {
+        let mut grid = [0.0; GRID_SIZE];
+        for (i, freq) in grid.iter_mut().enumerate() {
+            *freq = 20.0 * 1000.0f64.powf(i as f64 / (GRID_SIZE - 1) as f64);
+        }
+        CurveLoudness {
+            grid,
+            band_power: vec![[1.0; GRID_SIZE]; band_count],
+            measured: vec![None; band_count],
+            compensation: 0.0,
+        }
+    }
+
+    /// Same math as the DSP, bands that are switched off don't change anything
+    fn measure_band(&mut self, i: usize, band: &BandState, sample_rate: f64) {
+        let power = &mut self.band_power[i];
+        if band.enabled < 0.5 {
+            *power = [1.0; GRID_SIZE];
+            return;
+        }
+        let mut gain = band.gain;
+        if band.mode == 1.0 {
+            gain *= 0.5;
+        }
+        let kind = BandKind::from_u8(band.kind as u8);
+        let coeffs = get_coefficients(kind, band.freq, gain, band.bw, band.slope, sample_rate);
+        let coeffs = LinkwitzRileyCoefficients::from(coeffs);
+        let stereo = BandChannel::from_u8(band.channel as u8) == BandChannel::Stereo;
+        for (power, freq) in power.iter_mut().zip(self.grid.iter()) {
+            let z = ZSample::<f64>::new(freq.min(sample_rate * 0.49), sample_rate);
+            let magnitude = if band.mode == 0.0 {
+                coeffs.coeffs.get_bode_sample(z).norm()
+            } else {
+                coeffs.get_bode_sample(z).norm()
+            };
+            *power = if stereo {
+                magnitude * magnitude
+            } else {
+                (1.0 + magnitude * magnitude) * 0.5
+            };
+        }
+    }
+
+    /// dB that brings the average power of `bands` back to 0 dB.
+    /// Only the bands that changed since the last call are measured again.
+    pub fn compensation_db(&mut self, bands: &[BandState], sample_rate: f64) -> f64 {
+        let mut changed = false;
+        for (i, band) in bands.iter().enumerate().take(self.measured.len()) {
+            let measured = Some((*band, sample_rate));
+            if self.measured[i] != measured {
+                self.measure_band(i, band, sample_rate);
+                self.measured[i] = measured;
+                changed = true;
+            }
+        }
+        if changed {
+            let mut total = 0.0;
+            for i in 0..GRID_SIZE {
+                total += self
+                    .band_power
+                    .iter()
+                    .map(|power| power[i])
+                    .product::<f64>();
+            }
+            self.compensation = clamp_compensation(-10.0 * (total / GRID_SIZE as f64).log10());
+        }
+        self.compensation
+    }
+}
+
+/// Level of a stereo sample, as the RMS of both channels
+fn level(audio: f64x4) -> f32 {
+    let audio: [f64; 4] = audio.into();
+    ((audio[0] * audio[0] + audio[1] * audio[1]) * 0.5).sqrt() as f32
+}
+
+pub struct LiveLoudness {
+    input: AccumulatingRMS,
+    output: AccumulatingRMS,
+    input_rms: f32,
+    output_rms: f32,
+    compensation: f64,
+}
+
+impl LiveLoudness {
+    pub fn new(sample_rate: usize) -> LiveLoudness {
+        let max_size = (LIVE_MAX_SAMPLE_RATE as f32 * LIVE_WINDOW_MS / 1000.0) as usize;
+        LiveLoudness {
+            input: AccumulatingRMS::new(sample_rate, LIVE_WINDOW_MS, max_size),
+            output: AccumulatingRMS::new(sample_rate, LIVE_WINDOW_MS, max_size),
+            input_rms: 0.0,
+            output_rms: 0.0,
+            compensation: 0.0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        self.input.resize(sample_rate, LIVE_WINDOW_MS);
+        self.output.resize(sample_rate, LIVE_WINDOW_MS);
+    }
+
+    /// Audio going into the bands
+    pub fn measure_input(&mut self, block: &[f64x4]) {
+        for audio in block.iter() {
+            self.input_rms = self.input.process(level(*audio));
+        }
+    }
+
+    /// Audio coming out of the bands, before the output gain
+    pub fn measure_output(&mut self, block: &[f64x4]) {
+        for audio in block.iter() {
+            self.output_rms = self.output.process(level(*audio));
+        }
+        if self.input_rms > LIVE_SILENCE && self.output_rms > LIVE_SILENCE {
+            self.compensation =
+                clamp_compensation(20.0 * (self.input_rms as f64 / self.output_rms as f64).log10());
+        }
+    }
+
+    /// dB that brings the output back to the level of the input
+    pub fn compensation_db(&self) -> f64 {
+        self.compensation
+    }
+}
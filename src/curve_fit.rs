@@ -0,0 +1,435 @@
+//! Proposes bands that bring a measured response to a target curve
+
+use audio_filters::{linkwitz_riley::LinkwitzRileyCoefficients, units::ZSample};
+
+use crate::eq_effect_parameters::{BandKind, BandMode, EQEffectParameters};
+use crate::get_coefficients;
+use crate::state::{BandState, EQState, STATE_VERSION};
+
+/// Points the error is measured at, spaced evenly in log frequency
+const GRID_SIZE: usize = 96;
+/// Coordinate descent rounds when refining a band
+const REFINE_ITERATIONS: usize = 40;
+/// Rounds of refitting every band after one is added
+const BACKFIT_PASSES: usize = 2;
+/// Stop adding bands once one improves the error by less than this fraction
+const MIN_IMPROVEMENT: f64 = 0.01;
+/// Stop adding bands once the RMS error left is below this many dB
+const MIN_RMS_ERROR: f64 = 0.1;
+
+pub struct FitOptions {
+    pub max_boost: f64,
+    pub max_cut: f64,
+    pub sample_rate: f64,
+}
+
+/// Frequency (Hz) and level (dB) pairs, sorted by frequency
+pub type Response = Vec<(f64, f64)>;
+
+/// Reads the first two numeric columns of each line, as written by REW and most
+/// measurement software. Header and comment lines are skipped.
+pub fn parse_response(text: &str) -> Result<Response, String> {
+    let mut response = text
+        .lines()
+        .filter_map(|line| {
+            let mut values = line
+                .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse::<f64>());
+            match (values.next(), values.next()) {
+                (Some(Ok(freq)), Some(Ok(db))) if freq > 0.0 && db.is_finite() => Some((freq, db)),
+                _ => None,
+            }
+        })
+        .collect::<Response>();
+    if response.len() < 2 {
+        return Err("needs at least 2 lines of frequency and dB".to_string());
+    }
+    response.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    Ok(response)
+}
+
+/// Linear interpolation in log frequency, held flat past either end
+fn interpolate(response: &[(f64, f64)], freq: f64) -> f64 {
+    let i = response
+        .iter()
+        .position(|(f, _)| *f >= freq)
+        .unwrap_or_else(|| response.len());
+    if i == 0 {
+        return response[0].1;
+    }
+    if i == response.len() {
+        return response[i - 1].1;
+    }
+    let (f1, db1) = response[i - 1];
+    let (f2, db2) = response[i];
+    let t = (freq.ln() - f1.ln()) / (f2.ln() - f1.ln());
+    db1 + (db2 - db1) * t
+}
+
+/// Same math as the editor graph
+fn band_response_db(band: &BandState, grid: &[f64], fs: f64) -> Vec<f64> {
+    let coeffs = get_coefficients(
+        BandKind::from_u8(band.kind as u8),
+        band.freq,
+        band.gain,
+        band.bw,
+        band.slope,
+        fs,
+    );
+    let coeffs = LinkwitzRileyCoefficients::from(coeffs);
+    grid.iter()
+        .map(|f| {
+            let z = ZSample::<f64>::new(*f, fs);
+            20.0 * coeffs.coeffs.get_bode_sample(z).norm().log10()
+        })
+        .collect()
+}
+
+/// Squared error left after `band_db`, ignoring the average level of what is left
+fn error(residual: &[f64], band_db: &[f64]) -> f64 {
+    let mean = residual
+        .iter()
+        .zip(band_db.iter())
+        .map(|(r, b)| r - b)
+        .sum::<f64>()
+        / residual.len() as f64;
+    residual
+        .iter()
+        .zip(band_db.iter())
+        .map(|(r, b)| (r - b - mean).powi(2))
+        .sum()
+}
+
+struct Fitter<'a> {
+    grid: Vec<f64>,
+    options: &'a FitOptions,
+}
+
+impl<'a> Fitter<'a> {
+    fn score(&self, residual: &[f64], band: &BandState) -> f64 {
+        error(
+            residual,
+            &band_response_db(band, &self.grid, self.options.sample_rate),
+        )
+    }
+
+    fn clamp(&self, band: &mut BandState) {
+        let max_freq = (self.options.sample_rate * 0.45).min(20000.0);
+        band.freq = band.freq.max(20.0).min(max_freq);
+        band.gain = band
+            .gain
+            .max(-self.options.max_cut)
+            .min(self.options.max_boost);
+        band.bw = band.bw.max(0.1).min(6.0);
+    }
+
+    /// Coordinate descent over log frequency, gain and log bandwidth
+    fn refine(&self, residual: &[f64], mut band: BandState) -> (BandState, f64) {
+        let mut best = self.score(residual, &band);
+        let mut steps = [0.5f64, 3.0, 0.5];
+        for _ in 0..REFINE_ITERATIONS {
+            let mut improved = false;
+            for (i, step) in steps.iter().enumerate() {
+                for direction in [-1.0, 1.0].iter() {
+                    let mut candidate = band;
+                    match i {
+                        0 => candidate.freq *= (step * direction).exp2(),
+                        1 => candidate.gain += step * direction,
+                        _ => candidate.bw *= (step * direction).exp2(),
+                    }
+                    self.clamp(&mut candidate);
+                    let score = self.score(residual, &candidate);
+                    if score < best {
+                        best = score;
+                        band = candidate;
+                        improved = true;
+                    }
+                }
+            }
+            if !improved {
+                for step in steps.iter_mut() {
+                    *step *= 0.5;
+                }
+            }
+        }
+        (band, best)
+    }
+
+    /// Tries a bell at the largest error and a shelf at either end, keeping the best
+    fn best_band(&self, residual: &[f64]) -> (BandState, f64) {
+        let (peak_i, _) = residual
+            .iter()
+            .enumerate()
+            .fold((0, 0.0), |(best_i, best), (i, r)| {
+                if r.abs() > best {
+                    (i, r.abs())
+                } else {
+                    (best_i, best)
+                }
+            });
+        let third = self.grid.len() / 3;
+        let mean = |range: &[f64]| range.iter().sum::<f64>() / range.len() as f64;
+        let candidates = [
+            (BandKind::Bell, self.grid[peak_i], residual[peak_i]),
+            (
+                BandKind::LowShelf,
+                self.grid[third],
+                mean(&residual[..third]),
+            ),
+            (
+                BandKind::HighShelf,
+                self.grid[self.grid.len() - third],
+                mean(&residual[self.grid.len() - third..]),
+            ),
+        ];
+        candidates
+            .iter()
+            .map(|(kind, freq, gain)| {
+                let mut band = BandState {
+                    kind: *kind as u8 as f64,
+                    freq: *freq,
+                    gain: *gain,
+                    bw: 1.0,
+                    slope: 1.0,
+                    mode: BandMode::Butterworth as u8 as f64,
+                    ..BandState::default()
+                };
+                self.clamp(&mut band);
+                self.refine(residual, band)
+            })
+            .fold(
+                None,
+                |best: Option<(BandState, f64)>, candidate| match best {
+                    Some(best) if best.1 <= candidate.1 => Some(best),
+                    _ => Some(candidate),
+                },
+            )
+            .expect("there is always a candidate")
+    }
+}
+
+/// Fits up to `band_count` bands. The target is offset so its average level matches
+/// the measurement, since only the shape of the curves matters. Returns no bands if
+/// the measurement has fewer than 2 points or the target is empty.
+pub fn fit(
+    measured: &[(f64, f64)],
+    target: &[(f64, f64)],
+    band_count: usize,
+    options: &FitOptions,
+) -> Vec<BandState> {
+    if measured.len() < 2 || target.is_empty() {
+        return Vec::new();
+    }
+    let low = measured[0].0.max(20.0);
+    let high = measured[measured.len() - 1].0.min(20000.0);
+    let grid = (0..GRID_SIZE)
+        .map(|i| low * (high / low).powf(i as f64 / (GRID_SIZE - 1) as f64))
+        .collect::<Vec<f64>>();
+    let mut desired = grid
+        .iter()
+        .map(|f| interpolate(target, *f) - interpolate(measured, *f))
+        .collect::<Vec<f64>>();
+    let offset = desired.iter().sum::<f64>() / desired.len() as f64;
+    for d in desired.iter_mut() {
+        *d -= offset;
+    }
+
+    let fitter = Fitter { grid, options };
+    let mut bands: Vec<BandState> = Vec::new();
+    let mut residual = desired;
+    let mut current_error = error(&residual, &vec![0.0; residual.len()]);
+    while bands.len() < band_count
+        && (current_error / residual.len() as f64).sqrt() >= MIN_RMS_ERROR
+    {
+        let (band, new_error) = fitter.best_band(&residual);
+        if current_error - new_error <= current_error * MIN_IMPROVEMENT {
+            break;
+        }
+        let band_db = band_response_db(&band, &fitter.grid, options.sample_rate);
+        for (r, b) in residual.iter_mut().zip(band_db.iter()) {
+            *r -= b;
+        }
+        current_error = new_error;
+        bands.push(band);
+        // Each band was fitted before the ones after it, refit it against what the others leave
+        for _ in 0..BACKFIT_PASSES {
+            for band in bands.iter_mut() {
+                let band_db = band_response_db(band, &fitter.grid, options.sample_rate);
+                for (r, b) in residual.iter_mut().zip(band_db.iter()) {
+                    *r += b;
+                }
+                let (refined, refined_error) = fitter.refine(&residual, *band);
+                let band_db = band_response_db(&refined, &fitter.grid, options.sample_rate);
+                for (r, b) in residual.iter_mut().zip(band_db.iter()) {
+                    *r -= b;
+                }
+                *band = refined;
+                current_error = refined_error;
+            }
+        }
+    }
+    bands
+}
+
+/// Fits every band of `params` and applies the result, returning the number of bands used
+pub fn fit_to_params(
+    params: &EQEffectParameters,
+    measured: &[(f64, f64)],
+    target: &[(f64, f64)],
+    options: &FitOptions,
+) -> usize {
+    let bands = fit(measured, target, params.bands.len(), options);
+    let band_count = bands.len();
+    params.set_state(&EQState {
+        version: STATE_VERSION,
+        name: String::new(),
+        bands,
+        globals: None,
+    });
+    band_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: FitOptions = FitOptions {
+        max_boost: 12.0,
+        max_cut: 24.0,
+        sample_rate: 48000.0,
+    };
+
+    fn flat(db: f64) -> Response {
+        (0..=30)
+            .map(|i| (20.0 * 1000f64.powf(i as f64 / 30.0), db))
+            .collect()
+    }
+
+    /// The summed response of `bands`, read at 200 points from 20 Hz to 20 kHz
+    fn response_of(bands: &[BandState]) -> Response {
+        let freqs = (0..200)
+            .map(|i| 20.0 * 1000f64.powf(i as f64 / 199.0))
+            .collect::<Vec<f64>>();
+        let mut db = vec![0.0; freqs.len()];
+        for band in bands {
+            let band_db = band_response_db(band, &freqs, OPTIONS.sample_rate);
+            for (total, b) in db.iter_mut().zip(band_db.iter()) {
+                *total += b;
+            }
+        }
+        freqs.into_iter().zip(db).collect()
+    }
+
+    fn band(kind: BandKind, freq: f64, gain: f64, bw: f64) -> BandState {
+        BandState {
+            kind: kind as u8 as f64,
+            freq,
+            gain,
+            bw,
+            ..BandState::default()
+        }
+    }
+
+    fn assert_recovered(actual: &BandState, expected: &BandState) {
+        let octaves = (actual.freq / expected.freq).log2().abs();
+        assert!(
+            actual.kind == expected.kind
+                && octaves < 0.1
+                && (actual.gain - expected.gain).abs() < 0.5
+                && (actual.bw / expected.bw).log2().abs() < 0.2,
+            "fitted {:?} Hz {:?} dB {:?} oct (kind {:?}), expected {:?} Hz {:?} dB {:?} oct (kind {:?})",
+            actual.freq,
+            actual.gain,
+            actual.bw,
+            actual.kind,
+            expected.freq,
+            expected.gain,
+            expected.bw,
+            expected.kind
+        );
+    }
+
+    #[test]
+    fn parses_rew_exports() {
+        let text = "* Measurement data measured by REW\n\
+                    * Freq(Hz) SPL(dB) Phase(degrees)\n\
+                    1000.0, 80.5, 12.0\n\
+                    20.0 75.0 0.0\n\
+                    \n\
+                    20000;70.25\n";
+        assert_eq!(
+            parse_response(text).unwrap(),
+            vec![(20.0, 75.0), (1000.0, 80.5), (20000.0, 70.25)]
+        );
+    }
+
+    #[test]
+    fn skips_badly_formed_lines() {
+        let text = "100 nan\n-50 3\n0 3\n100\nabc 1\n200 x\n100 1\n400 2\n";
+        assert_eq!(
+            parse_response(text).unwrap(),
+            vec![(100.0, 1.0), (400.0, 2.0)]
+        );
+    }
+
+    #[test]
+    fn rejects_too_few_lines() {
+        assert!(parse_response("").is_err());
+        assert!(parse_response("Freq SPL\n1000 80\n").is_err());
+        assert!(parse_response("not a measurement\nat all\n").is_err());
+    }
+
+    #[test]
+    fn interpolates_in_log_frequency() {
+        let response = vec![(100.0, 0.0), (400.0, 6.0), (1000.0, -3.0)];
+        assert!((interpolate(&response, 200.0) - 3.0).abs() < 1e-9);
+        assert!((interpolate(&response, 400.0) - 6.0).abs() < 1e-9);
+        assert_eq!(interpolate(&response, 10.0), 0.0);
+        assert_eq!(interpolate(&response, 5000.0), -3.0);
+    }
+
+    #[test]
+    fn flat_response_needs_no_bands() {
+        assert!(fit(&flat(80.0), &flat(75.0), 8, &OPTIONS).is_empty());
+    }
+
+    #[test]
+    fn empty_input_needs_no_bands() {
+        assert!(fit(&[], &flat(0.0), 8, &OPTIONS).is_empty());
+        assert!(fit(&[(1000.0, 80.0)], &flat(0.0), 8, &OPTIONS).is_empty());
+        assert!(fit(&flat(0.0), &[], 8, &OPTIONS).is_empty());
+        assert!(fit(&flat(0.0), &flat(3.0), 0, &OPTIONS).is_empty());
+    }
+
+    #[test]
+    fn recovers_a_bell() {
+        let expected = band(BandKind::Bell, 1000.0, -6.0, 1.0);
+        let bands = fit(&flat(0.0), &response_of(&[expected]), 8, &OPTIONS);
+        assert_eq!(bands.len(), 1);
+        assert_recovered(&bands[0], &expected);
+    }
+
+    #[test]
+    fn recovers_a_bell_between_shelves() {
+        let expected = [
+            band(BandKind::LowShelf, 100.0, 6.0, 1.0),
+            band(BandKind::HighShelf, 6000.0, -6.0, 1.0),
+        ];
+        let bell = band(BandKind::Bell, 700.0, -8.0, 0.5);
+        let measured = response_of(&[
+            band(BandKind::LowShelf, 100.0, -6.0, 1.0),
+            band(BandKind::HighShelf, 6000.0, 6.0, 1.0),
+            band(BandKind::Bell, 700.0, 8.0, 0.5),
+        ]);
+        let bands = fit(&measured, &flat(0.0), 8, &OPTIONS);
+        assert_eq!(bands.len(), 3);
+        for expected in expected.iter().chain(std::iter::once(&bell)) {
+            let fitted = bands
+                .iter()
+                .find(|band| band.kind == expected.kind)
+                .expect("every kind of band is fitted");
+            assert_recovered(fitted, expected);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Dynamic bands, whose gain moves with the level of their own frequency range.
+//!
+//! The detector is a band-pass at the band's frequency and bandwidth, followed by a short RMS.
+//! It listens to the audio going into the band, or to the sidechain input with an external key.
+//! The sidechain has its own band-pass, so switching the key doesn't disturb either filter.
+//! Above the threshold the gain moves by `(level - threshold) * (1 - 1 / ratio)` dB,
+//! up to `range`. A negative range cuts and a positive range boosts.
+//! The gain follows with the attack and release times once per sub-block, and is added
+//! to the band's smoothed gain, so it glides no faster than the smoothing time.
+
+use audio_filters::filter_band::FilterBandCoefficients;
+use audio_filters::filter_band_wide::WideFilterBandCoefficients;
+use audio_filters::linkwitz_riley_wide::{WideLinkwitzRileyBand, WideLinkwitzRileyCoefficients};
+use wide::f64x4;
+
+use crate::eq_effect_parameters::{BandKey, BandKind};
+use crate::state::BandState;
+use crate::units::{AccumulatingRMS, Units};
+
+const DETECTOR_WINDOW_MS: f32 = 5.0;
+/// Sets the size of the RMS buffer, which is allocated up front
+const DETECTOR_MAX_SAMPLE_RATE: usize = 192000;
+/// Gain changes smaller than this (dB) don't update the coefficients
+const GAIN_TOLERANCE: f64 = 0.01;
+
+/// Only kinds with a gain can be dynamic, the others would show a gain that does nothing
+pub fn is_dynamic(band: &BandState) -> bool {
+    band.range != 0.0 && band.ratio > 1.0 && BandKind::from_u8(band.kind as u8).has_gain()
+}
+
+fn detector_band(freq: f64, bw: f64, sample_rate: f64) -> WideLinkwitzRileyCoefficients<f64x4> {
+    let coeffs = FilterBandCoefficients::bandpass(freq, 0.0, bw, sample_rate);
+    let coeffs = WideFilterBandCoefficients::from(coeffs);
+    WideLinkwitzRileyCoefficients::from(coeffs)
+}
+
+pub struct BandDynamics {
+    detector: WideLinkwitzRileyBand<f64x4>,
+    sidechain_detector: WideLinkwitzRileyBand<f64x4>,
+    /// Freq, bandwidth and sample rate the detector is set to
+    detector_settings: (f64, f64, f64),
+    rms: AccumulatingRMS,
+    /// dB added to the band's gain
+    gain: f64,
+    /// `gain` when the coefficients were last updated
+    applied_gain: f64,
+}
+
+impl BandDynamics {
+    pub fn new(sample_rate: f64) -> BandDynamics {
+        let coeffs = detector_band(1000.0, 1.0, sample_rate);
+        let max_size = (DETECTOR_MAX_SAMPLE_RATE as f32 * DETECTOR_WINDOW_MS / 1000.0) as usize;
+        BandDynamics {
+            detector: WideLinkwitzRileyBand::from(&coeffs),
+            sidechain_detector: WideLinkwitzRileyBand::from(&coeffs),
+            detector_settings: (1000.0, 1.0, sample_rate),
+            rms: AccumulatingRMS::new(sample_rate as usize, DETECTOR_WINDOW_MS, max_size),
+            gain: 0.0,
+            applied_gain: 0.0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.rms.resize(sample_rate as usize, DETECTOR_WINDOW_MS);
+    }
+
+    /// Only recalculates the detector when the band moved
+    pub fn set_detector(&mut self, freq: f64, bw: f64, sample_rate: f64) {
+        if self.detector_settings == (freq, bw, sample_rate) {
+            return;
+        }
+        let coeffs = detector_band(freq, bw, sample_rate);
+        self.detector.update(&coeffs);
+        self.sidechain_detector.update(&coeffs);
+        self.detector_settings = (freq, bw, sample_rate);
+    }
+
+    /// Runs the detector over `input`, the audio going into the band, or over `sidechain`
+    /// when the band has an external key, and moves the gain along. Both are in the same
+    /// left/right or mid/side domain as the band, and only the `lanes` it changes are measured.
+    /// Bands that aren't dynamic release to 0.
+    pub fn process(
+        &mut self,
+        input: &[f64x4],
+        sidechain: &[f64x4],
+        lanes: [f64; 2],
+        band: &BandState,
+        sample_rate: f64,
+    ) {
+        if self.gain == 0.0 && !is_dynamic(band) {
+            return;
+        }
+        let target = if is_dynamic(band) {
+            let (detector, key) = match BandKey::from_u8(band.key as u8) {
+                BandKey::Internal => (&mut self.detector, input),
+                BandKey::External => (&mut self.sidechain_detector, sidechain),
+            };
+            let mut level = 0.0f32;
+            for audio in key.iter() {
+                let filtered: f64x4 =
+                    (detector.filter1.process)(&mut detector.filter1, *audio).into();
+                let filtered: [f64; 4] = filtered.into();
+                let power = (filtered[0] * filtered[0] * lanes[0]
+                    + filtered[1] * filtered[1] * lanes[1])
+                    / (lanes[0] + lanes[1]);
+                level = level.max(self.rms.process(power.sqrt() as f32));
+            }
+            let over = ((level as f64).lin_to_db() - band.threshold).max(0.0);
+            (over * (1.0 - 1.0 / band.ratio)).min(band.range.abs()) * band.range.signum()
+        } else {
+            0.0
+        };
+        let time_ms = if target.abs() > self.gain.abs() {
+            band.attack
+        } else {
+            band.release
+        };
+        let factor = 1.0 - (-(key.len() as f64) / (time_ms / 1000.0 * sample_rate)).exp();
+        self.gain += (target - self.gain) * factor;
+        if (target - self.gain).abs() < GAIN_TOLERANCE * 0.1 {
+            self.gain = target;
+        }
+    }
+
+    pub fn gain_db(&self) -> f64 {
+        self.gain
+    }
+
+    /// The gain moved enough since the coefficients were updated to be heard
+    pub fn gain_changed(&self) -> bool {
+        (self.gain - self.applied_gain).abs() > GAIN_TOLERANCE
+    }
+
+    /// The gain to add to the band's gain
+    pub fn apply_gain(&mut self) -> f64 {
+        self.applied_gain = self.gain;
+        self.gain
+    }
+}
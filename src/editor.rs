@@ -2,66 +2,91 @@ use audio_filters::{linkwitz_riley::LinkwitzRileyCoefficients, units::Units, uni
 use imgui::*;
 
 use crate::{
-    atomic_f64::AtomicF64,
-    editor_elements::*,
-    eq_effect_parameters::{BandKind, BandMode},
+    atomic_f64::AtomicF64, editor_elements::*, eq_effect_parameters::BandParameters,
     get_coefficients,
 };
 
 use crate::units::map_to_freq;
 use imgui_baseview::{HiDpiMode, ImguiWindow, RenderSettings, Settings};
 
-use crate::eq_effect_parameters::EQEffectParameters;
+use crate::curve_fit::{self, FitOptions};
+use crate::eq_effect_parameters::{AutoGain, BandChannel, EQEffectParameters};
+use crate::equalizer_apo;
+use crate::linear_phase::{self, LatencyReporter};
 use crate::parameter::Parameter;
+use crate::preset_file::{self, PresetBand, PresetFile};
+use crate::state::BandState;
+use crate::undo_history::UndoHistory;
 
 use vst::editor::Editor;
 
 use baseview::{Size, WindowOpenOptions, WindowScalePolicy};
 
 use raw_window_handle::{HasRawWindowHandle, RawWindowHandle};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 const WINDOW_WIDTH: usize = 1300;
 const WINDOW_HEIGHT: usize = 1300;
 const WINDOW_WIDTH_F: f32 = WINDOW_WIDTH as f32;
 const WINDOW_HEIGHT_F: f32 = WINDOW_HEIGHT as f32;
 
-fn input_float(ui: &Ui, parameter: &Parameter, i: usize) {
-    let knob_id = &ImString::new(format!("##{}_{}_KNOB_CONTORL_", parameter.get_name(), i));
-    let mut val = parameter.get();
-
-    let nor_val = parameter.get_normalized();
+/// Curves of bands on one channel are drawn on top of the stereo curve in these colors
+fn channel_color(channel: BandChannel) -> [f32; 4] {
+    match channel {
+        BandChannel::Stereo => [1.0, 1.0, 1.0, 0.9],
+        BandChannel::Left => [0.4, 0.6, 1.0, 0.9],
+        BandChannel::Right => [1.0, 0.45, 0.4, 0.9],
+        BandChannel::Mid => [0.5, 1.0, 0.5, 0.9],
+        BandChannel::Side => [1.0, 0.8, 0.3, 0.9],
+    }
+}
 
-    let speed = (((parameter.transform_func)(nor_val) / (parameter.inv_transform_func)(nor_val))
-        .abs()
-        * (parameter.max - parameter.min))
-        .max(0.00001) as f32;
+/// Returns whether the value is being dragged
+fn input_float(
+    ui: &Ui,
+    params: &EQEffectParameters,
+    history: &mut UndoHistory,
+    parameter: &Parameter,
+    i: usize,
+) -> bool {
+    let knob_id = &ImString::new(format!("##{}_{}_KNOB_CONTORL_", parameter.get_name(), i));
+    // Dragging follows the parameter's mapping, the same as host automation
+    let mut val = parameter.get_normalized();
     let cursor = ui.cursor_pos();
     if Drag::new(knob_id)
-        .range(parameter.min..=parameter.max)
-        .speed(speed * 0.001)
+        .range(0.0..=1.0)
+        .speed(0.001)
         .display_format(im_str!(""))
         .build(ui, &mut val)
     {
-        //parameter.set(*knob.p_value)
-        parameter.set(val)
+        parameter.set_normalized(val)
+    }
+    // A whole drag is a single undo step
+    if ui.is_item_activated() {
+        history.begin_gesture(params, parameter);
+    }
+    if ui.is_item_deactivated() {
+        history.end_gesture(params);
     }
+    let active = ui.is_item_active();
 
     let cursor2 = ui.cursor_pos();
     ui.set_cursor_pos(cursor);
 
-    if !ui.is_item_active() || ui.is_mouse_down(MouseButton::Left) {
+    if !active || ui.is_mouse_down(MouseButton::Left) {
         ui.text(&ImString::new(format!("{}", parameter.get_display())));
     }
     ui.set_cursor_pos(cursor2);
+    active
 }
 
-fn popup_select<F: Fn(usize) -> bool>(
+/// Lists the choices of a stepped parameter
+fn popup_select(
     ui: &Ui,
+    params: &EQEffectParameters,
+    history: &mut UndoHistory,
     parameter: &Parameter,
     i: usize,
-    button_fn: F,
-    qty_of_options: usize,
 ) {
     let popup_str = &ImString::new(format!("band_{}_popup{}", parameter.get_name(), i));
     if ui.button(
@@ -76,10 +101,10 @@ fn popup_select<F: Fn(usize) -> bool>(
         ui.open_popup(popup_str);
     }
     ui.popup(popup_str, || {
-        //let kind = parameter.get() as i32;
-        for j in 0..qty_of_options {
-            if button_fn(j) {
-                parameter.set(j as f64);
+        let selected = (parameter.get() - parameter.min) as usize;
+        for (j, choice) in parameter.choices().iter().enumerate() {
+            if ui.radio_button_bool(&ImString::new(choice.as_str()), selected == j) {
+                history.set(params, parameter, parameter.min + j as f64);
                 ui.close_current_popup();
                 break;
             }
@@ -87,9 +112,412 @@ fn popup_select<F: Fn(usize) -> bool>(
     });
 }
 
+/// Toggles the band that stays soloed after dragging stops
+fn solo_button(ui: &Ui, latched_solo: &mut Option<usize>, i: usize) {
+    let label = if *latched_solo == Some(i) {
+        "Soloed"
+    } else {
+        "Solo"
+    };
+    if ui.button(&ImString::new(format!("{}##solo_{}", label, i)), [0.0, 0.0]) {
+        *latched_solo = if *latched_solo == Some(i) {
+            None
+        } else {
+            Some(i)
+        };
+    }
+}
+
+/// An Off/On parameter as a checkbox
+fn checkbox(
+    ui: &Ui,
+    params: &EQEffectParameters,
+    history: &mut UndoHistory,
+    parameter: &Parameter,
+    i: usize,
+) {
+    let mut on = parameter.get() >= 0.5;
+    if ui.checkbox(
+        &ImString::new(format!("On##{}_{}", parameter.get_name(), i)),
+        &mut on,
+    ) {
+        history.set(params, parameter, if on { 1.0 } else { 0.0 });
+    }
+}
+
+fn draw_undo_redo(ui: &Ui, params: &EQEffectParameters, history: &mut UndoHistory) {
+    let io = ui.io();
+    // Typing a preset name or a path keeps its own undo
+    let shortcuts = io.key_ctrl && !io.want_text_input;
+    if shortcuts && ui.is_key_pressed(Key::Z) {
+        if io.key_shift {
+            history.redo(params);
+        } else {
+            history.undo(params);
+        }
+    } else if shortcuts && ui.is_key_pressed(Key::Y) {
+        history.redo(params);
+    }
+
+    if ui.button(im_str!("Undo"), [0.0, 0.0]) && history.can_undo() {
+        history.undo(params);
+    }
+    ui.same_line(0.0);
+    if ui.button(im_str!("Redo"), [0.0, 0.0]) && history.can_redo() {
+        history.redo(params);
+    }
+}
+
+fn draw_ab_comparison(ui: &Ui, params: &EQEffectParameters) {
+    let active = params.active_ab_slot();
+    if ui.button(
+        &ImString::new(format!("Comparing {}##ab_toggle", active)),
+        [0.0, 0.0],
+    ) {
+        params.toggle_ab();
+    }
+    ui.same_line(0.0);
+    if ui.button(im_str!("Copy A to B"), [0.0, 0.0]) {
+        params.copy_a_to_b();
+    }
+}
+
+fn draw_gain_stage(
+    ui: &Ui,
+    params: &EQEffectParameters,
+    history: &mut UndoHistory,
+    compensation: f64,
+) {
+    ui.text(im_str!("Input Trim"));
+    ui.same_line(0.0);
+    input_float(ui, params, history, &params.input_trim, 0);
+    ui.same_line(0.0);
+    ui.text(im_str!("Output Gain"));
+    ui.same_line(0.0);
+    input_float(ui, params, history, &params.output_gain, 0);
+    ui.same_line(0.0);
+    ui.text(im_str!("Auto Gain"));
+    ui.same_line(0.0);
+    popup_select(ui, params, history, &params.auto_gain, 0);
+    if params.get_auto_gain() != AutoGain::Off {
+        ui.same_line(0.0);
+        ui.text(&ImString::new(format!("{:+.1} dB", compensation)));
+    }
+}
+
+/// Linear phase switch and kernel length, with the latency they add
+fn draw_linear_phase(
+    ui: &Ui,
+    params: &EQEffectParameters,
+    history: &mut UndoHistory,
+    sample_rate: f64,
+) {
+    ui.text(im_str!("Linear Phase"));
+    ui.same_line(0.0);
+    checkbox(ui, params, history, &params.linear_phase, 0);
+    ui.same_line(0.0);
+    popup_select(ui, params, history, &params.fir_length, 0);
+    if params.is_linear_phase() {
+        let latency = linear_phase::latency(params.get_fir_length());
+        ui.same_line(0.0);
+        ui.text(&ImString::new(format!(
+            "{:.1} ms latency",
+            latency as f64 / sample_rate * 1000.0
+        )));
+    }
+}
+
+/// Detector and timing of a band's dynamics, with the gain they add right now.
+/// Linear phase runs every band at its static gain, so they are greyed out while it is on.
+fn draw_band_dynamics(
+    ui: &Ui,
+    params: &EQEffectParameters,
+    history: &mut UndoHistory,
+    band: &BandParameters,
+    i: usize,
+    dynamic_gain: f64,
+) {
+    let linear_phase = params.is_linear_phase();
+    if linear_phase {
+        ui.text_disabled(im_str!("Dynamics (off in linear phase)"));
+    } else {
+        ui.text(im_str!("Dynamics"));
+    }
+    let greyed = if linear_phase {
+        Some(ui.push_style_var(StyleVar::Alpha(0.5)))
+    } else {
+        None
+    };
+    popup_select(ui, params, history, &band.key, i);
+    for parameter in [
+        &band.threshold,
+        &band.ratio,
+        &band.attack,
+        &band.release,
+        &band.range,
+    ]
+    .iter()
+    {
+        input_float(ui, params, history, parameter, i);
+    }
+    if let Some(greyed) = greyed {
+        greyed.pop(ui);
+    }
+    if !linear_phase && dynamic_gain != 0.0 {
+        ui.text(&ImString::new(format!("{:+.1} dB", dynamic_gain)));
+    }
+}
+
+/// Copies one band to the clipboard as preset text
+fn copy_band(ui: &Ui, band: &BandParameters) -> Result<(), String> {
+    let text = PresetBand::from(&band.get_state())
+        .to_text()
+        .map_err(|e| e.to_string())?;
+    ui.set_clipboard_text(&ImString::new(text));
+    Ok(())
+}
+
+/// The clipboard text is fully parsed before the band is changed
+fn paste_band(ui: &Ui, band: &BandParameters) -> Result<(), String> {
+    let text = ui
+        .clipboard_text()
+        .ok_or_else(|| "clipboard is empty".to_string())?;
+    let preset = PresetBand::from_text(text.to_str()).map_err(|e| e.to_string())?;
+    band.set_state(&BandState::from(&preset));
+    Ok(())
+}
+
+fn draw_band_clipboard(ui: &Ui, band: &BandParameters, i: usize, message: &mut String) {
+    if ui.button(&ImString::new(format!("Copy##copy_band{}", i)), [0.0, 0.0]) {
+        *message = match copy_band(ui, band) {
+            Ok(()) => format!("Copied band {}", i + 1),
+            Err(e) => format!("Could not copy band {}: {}", i + 1, e),
+        };
+    }
+    ui.same_line(0.0);
+    if ui.button(
+        &ImString::new(format!("Paste##paste_band{}", i)),
+        [0.0, 0.0],
+    ) {
+        *message = match paste_band(ui, band) {
+            Ok(()) => format!("Pasted into band {}", i + 1),
+            Err(e) => format!("Could not paste into band {}: {}", i + 1, e),
+        };
+    }
+}
+
+fn draw_eq_clipboard(ui: &Ui, params: &EQEffectParameters, message: &mut String) {
+    if ui.button(im_str!("Copy EQ"), [0.0, 0.0]) {
+        *message = match PresetFile::from_params(params).to_text() {
+            Ok(text) => {
+                ui.set_clipboard_text(&ImString::new(text));
+                "Copied all bands".to_string()
+            }
+            Err(e) => format!("Could not copy: {}", e),
+        };
+    }
+    ui.same_line(0.0);
+    if ui.button(im_str!("Paste EQ"), [0.0, 0.0]) {
+        let preset = match ui.clipboard_text() {
+            Some(text) => PresetFile::from_text(text.to_str()).map_err(|e| e.to_string()),
+            None => Err("clipboard is empty".to_string()),
+        };
+        *message = match preset {
+            Ok(preset) => {
+                preset.apply(params);
+                "Pasted all bands".to_string()
+            }
+            Err(e) => format!("Could not paste: {}", e),
+        };
+    }
+}
+
+pub struct PresetBrowser {
+    pub name: ImString,
+    pub apo_path: ImString,
+    pub presets: Vec<String>,
+    pub message: String,
+}
+
+impl Default for PresetBrowser {
+    fn default() -> Self {
+        let mut browser = PresetBrowser {
+            name: ImString::with_capacity(128),
+            apo_path: ImString::with_capacity(512),
+            presets: Vec::new(),
+            message: String::new(),
+        };
+        browser.refresh();
+        browser
+    }
+}
+
+impl PresetBrowser {
+    pub fn refresh(&mut self) {
+        match preset_file::list_presets() {
+            Ok(presets) => self.presets = presets,
+            Err(e) => self.message = format!("Could not list presets: {}", e),
+        }
+    }
+}
+
+fn draw_equalizer_apo(ui: &Ui, params: &EQEffectParameters, browser: &mut PresetBrowser) {
+    ui.input_text(im_str!("Equalizer APO file"), &mut browser.apo_path)
+        .build();
+    ui.same_line(0.0);
+    if ui.button(im_str!("Import"), [0.0, 0.0]) {
+        let path = browser.apo_path.to_str().to_string();
+        browser.message = match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                let warnings = equalizer_apo::import(params, &text);
+                if warnings.is_empty() {
+                    format!("Imported {}", path)
+                } else {
+                    format!("Imported {} with warnings:\n{}", path, warnings.join("\n"))
+                }
+            }
+            Err(e) => format!("Could not read {}: {}", path, e),
+        };
+    }
+    ui.same_line(0.0);
+    if ui.button(im_str!("Export"), [0.0, 0.0]) {
+        let path = browser.apo_path.to_str().to_string();
+        let export = equalizer_apo::export(params);
+        browser.message = match std::fs::write(&path, export.text) {
+            Ok(()) if export.warnings.is_empty() => format!("Exported {}", path),
+            Ok(()) => format!(
+                "Exported {} with warnings:\n{}",
+                path,
+                export.warnings.join("\n")
+            ),
+            Err(e) => format!("Could not write {}: {}", path, e),
+        };
+    }
+}
+
+fn draw_preset_browser(ui: &Ui, params: &EQEffectParameters, browser: &mut PresetBrowser) {
+    ui.input_text(im_str!("Preset name"), &mut browser.name)
+        .build();
+    ui.same_line(0.0);
+    if ui.button(im_str!("Save"), [0.0, 0.0]) {
+        let name = browser.name.to_str().to_string();
+        browser.message = match preset_file::save_preset(params, &name) {
+            Ok(()) => format!("Saved {}", name),
+            Err(e) => format!("Could not save {}: {}", name, e),
+        };
+        browser.refresh();
+    }
+    ui.same_line(0.0);
+    if ui.button(im_str!("Load"), [0.0, 0.0]) {
+        let name = browser.name.to_str().to_string();
+        browser.message = match preset_file::load_preset(params, &name) {
+            Ok(()) => format!("Loaded {}", name),
+            Err(e) => format!("Could not load {}: {}", name, e),
+        };
+    }
+    ui.same_line(0.0);
+    if ui.button(im_str!("Refresh"), [0.0, 0.0]) {
+        browser.refresh();
+    }
+    draw_equalizer_apo(ui, params, browser);
+    ui.text(&ImString::new(&browser.message));
+
+    ChildWindow::new(im_str!("preset_list"))
+        .size([0.0, 200.0])
+        .border(true)
+        .build(ui, || {
+            let mut clicked = None;
+            for preset in browser.presets.iter() {
+                if Selectable::new(&ImString::new(preset))
+                    .selected(browser.name.to_str() == preset)
+                    .build(ui)
+                {
+                    clicked = Some(preset.clone());
+                }
+            }
+            if let Some(preset) = clicked {
+                browser.name.clear();
+                browser.name.push_str(&preset);
+            }
+        });
+}
+
+pub struct CurveFitPanel {
+    pub measured_path: ImString,
+    pub target_path: ImString,
+    pub max_boost: f32,
+    pub message: String,
+}
+
+impl Default for CurveFitPanel {
+    fn default() -> Self {
+        CurveFitPanel {
+            measured_path: ImString::with_capacity(512),
+            target_path: ImString::with_capacity(512),
+            max_boost: 6.0,
+            message: String::new(),
+        }
+    }
+}
+
+fn read_response(path: &str) -> Result<curve_fit::Response, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    curve_fit::parse_response(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn draw_curve_fit(
+    ui: &Ui,
+    params: &EQEffectParameters,
+    sample_rate: f64,
+    panel: &mut CurveFitPanel,
+) {
+    ui.input_text(im_str!("Measurement csv"), &mut panel.measured_path)
+        .build();
+    ui.input_text(
+        im_str!("Target csv (empty for flat)"),
+        &mut panel.target_path,
+    )
+    .build();
+    ui.input_float(im_str!("Max boost dB"), &mut panel.max_boost)
+        .build();
+    panel.max_boost = panel.max_boost.max(0.0).min(24.0);
+    if ui.button(im_str!("Fit to target"), [0.0, 0.0]) {
+        let target = if panel.target_path.to_str().trim().is_empty() {
+            Ok(vec![(20.0, 0.0), (20000.0, 0.0)])
+        } else {
+            read_response(panel.target_path.to_str())
+        };
+        let options = FitOptions {
+            max_boost: panel.max_boost as f64,
+            max_cut: 24.0,
+            sample_rate,
+        };
+        panel.message = match (read_response(panel.measured_path.to_str()), target) {
+            (Ok(measured), Ok(target)) => {
+                let band_count = curve_fit::fit_to_params(params, &measured, &target, &options);
+                format!("Fitted with {} bands", band_count)
+            }
+            (Err(e), _) | (_, Err(e)) => format!("Could not fit: {}", e),
+        };
+    }
+    ui.text(&ImString::new(&panel.message));
+}
+
 pub struct EditorState {
     pub params: Arc<EQEffectParameters>,
     pub sample_rate: Arc<AtomicF64>,
+    /// Auto gain the DSP is applying, in dB
+    pub compensation: Arc<AtomicF64>,
+    /// Gain each dynamic band is adding right now, in dB
+    pub dynamic_gain: Arc<Vec<AtomicF64>>,
+    pub preset_browser: Mutex<PresetBrowser>,
+    pub history: Mutex<UndoHistory>,
+    pub curve_fit: Mutex<CurveFitPanel>,
+    /// Result of the last copy or paste
+    pub clipboard_message: Mutex<String>,
+    /// Band soloed with its button. Dragging a band solos it until the drag ends.
+    pub latched_solo: Mutex<Option<usize>>,
+    pub latency_reporter: Arc<LatencyReporter>,
 }
 
 pub struct EQPluginEditor {
@@ -167,11 +595,33 @@ impl Editor for EQPluginEditor {
 
                     let params = &state.params;
 
-                    let mut graph_y_values = vec![0.0f32; graph_width as usize];
+                    // One curve for the stereo bands, and one for each channel that has bands of its own
+                    let band_channels = params
+                        .bands
+                        .iter()
+                        .map(|band| band.get_channel())
+                        .collect::<Vec<BandChannel>>();
+                    let channels = (0..BandChannel::COUNT)
+                        .map(|i| BandChannel::from_u8(i as u8))
+                        .filter(|channel| {
+                            *channel == BandChannel::Stereo
+                                || params.bands.iter().zip(band_channels.iter()).any(
+                                    |(band, band_channel)| {
+                                        band.is_enabled() && band_channel == channel
+                                    },
+                                )
+                        })
+                        .collect::<Vec<BandChannel>>();
+                    let mut curves = vec![vec![0.0f32; graph_width as usize]; channels.len()];
+                    // The same curves with the gain dynamic bands have right now
+                    let mut dynamic_curves = curves.clone();
 
                     let mut bandcoeffs = Vec::new();
+                    let mut dynamic_bandcoeffs = Vec::new();
 
-                    for band in state.params.bands.iter() {
+                    for (band, dynamic_gain) in
+                        state.params.bands.iter().zip(state.dynamic_gain.iter())
+                    {
                         //TODO reuse coeffs from DSP
 
                         let f0 = band.freq.get() as f32;
@@ -188,95 +638,184 @@ impl Editor for EQPluginEditor {
                         };
                         let coeffs = get_coefficients(band.get_kind(), f0, gain, bw, slope, fs);
                         bandcoeffs.push(LinkwitzRileyCoefficients::from(coeffs));
+
+                        let mut dynamic_gain = dynamic_gain.get() as f32;
+                        dynamic_bandcoeffs.push(if dynamic_gain.abs() > 0.01 {
+                            if mode == 1.0 {
+                                dynamic_gain *= 0.5;
+                            }
+                            let coeffs = get_coefficients(
+                                band.get_kind(),
+                                f0,
+                                gain + dynamic_gain,
+                                bw,
+                                slope,
+                                fs,
+                            );
+                            Some(LinkwitzRileyCoefficients::from(coeffs))
+                        } else {
+                            None
+                        });
                     }
+                    let any_dynamic = dynamic_bandcoeffs.iter().any(Option::is_some);
 
-                    for (i, graph_y) in graph_y_values.iter_mut().enumerate() {
+                    for i in 0..graph_width as usize {
                         let f_hz = map_to_freq((i as f32) / graph_width) as f64;
                         let z = ZSample::<f32>::new(f_hz as f32, sample_rate as f32);
                         for (band_n, coeffs) in bandcoeffs.iter().enumerate() {
-                            let y = if state.params.bands[band_n].get_mode() == 0.0 {
+                            let band = &state.params.bands[band_n];
+                            if !band.is_enabled() {
+                                continue;
+                            }
+                            let butterworth = band.get_mode() == 0.0;
+                            let y = if butterworth {
                                 coeffs.coeffs.get_bode_sample(z).norm()
                             } else {
                                 coeffs.get_bode_sample(z).norm()
                             };
-                            *graph_y += -(y.lin_to_db()) as f32;
+                            let db = -(y.lin_to_db()) as f32;
+                            let dynamic_db = match &dynamic_bandcoeffs[band_n] {
+                                Some(coeffs) if butterworth => {
+                                    -(coeffs.coeffs.get_bode_sample(z).norm().lin_to_db()) as f32
+                                }
+                                Some(coeffs) => {
+                                    -(coeffs.get_bode_sample(z).norm().lin_to_db()) as f32
+                                }
+                                None => db,
+                            };
+                            let band_channel = band_channels[band_n];
+                            let band_curves = curves.iter_mut().zip(dynamic_curves.iter_mut());
+                            for ((curve, dynamic_curve), channel) in
+                                band_curves.zip(channels.iter())
+                            {
+                                if band_channel == BandChannel::Stereo || band_channel == *channel {
+                                    curve[i] += db;
+                                    dynamic_curve[i] += dynamic_db;
+                                }
+                            }
                             //let y = -new_band.get_bode_sample(z).arg().to_degrees() * 0.2;
                             //*graph_y += y as f32;
                         }
                     }
 
-                    draw_eq_graph(
+                    let curve_pos = draw_eq_graph(
                         ui,
                         im_str!("test"),
                         [graph_width, graph_height],
                         db_px_step,
                         2.0,
                         graph_width as usize,
-                        |i| graph_y_values[i],
+                        |i| curves[0][i],
                     );
+                    for (curve, channel) in curves.iter().zip(channels.iter()).skip(1) {
+                        draw_eq_curve(
+                            ui,
+                            curve_pos,
+                            [graph_width, graph_height],
+                            db_px_step,
+                            2.0,
+                            channel_color(*channel),
+                            graph_width as usize,
+                            |i| curve[i],
+                        );
+                    }
+                    if any_dynamic {
+                        for (curve, channel) in dynamic_curves.iter().zip(channels.iter()) {
+                            let mut color = channel_color(*channel);
+                            color[3] = 0.5;
+                            draw_eq_curve(
+                                ui,
+                                curve_pos,
+                                [graph_width, graph_height],
+                                db_px_step,
+                                1.5,
+                                color,
+                                graph_width as usize,
+                                |i| curve[i],
+                            );
+                        }
+                    }
+                    if channels.len() > 1 {
+                        for (n, channel) in channels.iter().enumerate() {
+                            if n > 0 {
+                                ui.same_line(0.0);
+                            }
+                            ui.text_colored(
+                                channel_color(*channel),
+                                &ImString::new(channel.to_string()),
+                            );
+                        }
+                    }
                     //ui.radio_button_bool(
                     //    &ImString::new(format!("{}", BandKind::from_u8(j as u8).to_string())),
                     //    kind == j,
                     //)
+                    let mut history = match state.history.lock() {
+                        Ok(history) => history,
+                        Err(_) => return,
+                    };
+                    let mut clipboard_message = match state.clipboard_message.lock() {
+                        Ok(message) => message,
+                        Err(_) => return,
+                    };
+                    let mut latched_solo = match state.latched_solo.lock() {
+                        Ok(solo) => solo,
+                        Err(_) => return,
+                    };
+                    let mut dragged_band = None;
                     ui.columns(4, im_str!("cols"), false);
                     for (i, band) in params.bands.iter().enumerate() {
-                        popup_select(
-                            ui,
-                            &band.kind,
-                            i,
-                            |j| {
-                                ui.radio_button_bool(
-                                    &ImString::new(format!(
-                                        "{}",
-                                        BandKind::from_u8(j as u8).to_string()
-                                    )),
-                                    band.get_kind() as usize == j,
-                                )
-                            },
-                            8,
-                        );
+                        checkbox(ui, params, &mut history, &band.enabled, i);
+                        ui.same_line(0.0);
+                        popup_select(ui, params, &mut history, &band.kind, i);
+                        ui.same_line(0.0);
+                        solo_button(ui, &mut latched_solo, i);
                         //input_float(&ui, &band.kind, i);
-                        input_float(&ui, &band.freq, i);
-                        input_float(&ui, &band.gain, i);
-                        input_float(&ui, &band.bw, i);
-                        input_float(&ui, &band.slope, i);
-                        let popup_str = &ImString::new(format!("db/oct##_popupbtn{}", i));
-                        if ui.button(
-                            &ImString::new(format!("{}dB/oct", band.slope.get() as u32 * 6)),
-                            [0.0, 0.0],
-                        ) {
-                            ui.open_popup(popup_str);
-                        }
-                        ui.popup(popup_str, || {
-                            let slope = band.get_slope() as i32;
-                            for j in 1..=16 {
-                                if ui.radio_button_bool(
-                                    &ImString::new(format!("{}dB/oct", j * 6)),
-                                    slope == j,
-                                ) {
-                                    band.slope.set(j as f64);
-                                    ui.close_current_popup();
-                                    break;
-                                }
+                        // Dragging the shape of a band solos it
+                        for parameter in [&band.freq, &band.gain, &band.bw].iter() {
+                            if input_float(&ui, params, &mut history, parameter, i) {
+                                dragged_band = Some(i);
                             }
-                        });
-                        popup_select(
-                            ui,
-                            &band.mode,
-                            i,
-                            |j| {
-                                ui.radio_button_bool(
-                                    &ImString::new(format!(
-                                        "{}",
-                                        BandMode::from_u8(j as u8).to_string()
-                                    )),
-                                    band.get_mode() as usize == j,
-                                )
-                            },
-                            2,
-                        );
+                        }
+                        popup_select(ui, params, &mut history, &band.slope, i);
+                        popup_select(ui, params, &mut history, &band.mode, i);
+                        popup_select(ui, params, &mut history, &band.channel, i);
+                        // Only kinds with a gain can be dynamic
+                        if band.get_kind().has_gain() {
+                            let dynamic_gain = state.dynamic_gain[i].get();
+                            draw_band_dynamics(ui, params, &mut history, band, i, dynamic_gain);
+                        }
+                        draw_band_clipboard(ui, band, i, &mut clipboard_message);
                         ui.next_column();
                     }
+                    ui.columns(1, im_str!("presets"), false);
+                    params.set_solo(dragged_band.or(*latched_solo));
+                    ui.separator();
+                    draw_undo_redo(ui, params, &mut history);
+                    ui.same_line(0.0);
+                    draw_ab_comparison(ui, params);
+                    ui.same_line(0.0);
+                    draw_eq_clipboard(ui, params, &mut clipboard_message);
+                    ui.same_line(0.0);
+                    ui.text(im_str!("Smoothing"));
+                    ui.same_line(0.0);
+                    input_float(&ui, params, &mut history, &params.smoothing, 0);
+                    if params.bypass.get() {
+                        ui.same_line(0.0);
+                        ui.text(im_str!("Bypassed by the host"));
+                    }
+                    draw_gain_stage(ui, params, &mut history, state.compensation.get());
+                    draw_linear_phase(ui, params, &mut history, sample_rate);
+                    if !clipboard_message.is_empty() {
+                        ui.text(&ImString::new(clipboard_message.as_str()));
+                    }
+                    if let Ok(mut browser) = state.preset_browser.lock() {
+                        draw_preset_browser(ui, params, &mut browser);
+                    }
+                    ui.separator();
+                    if let Ok(mut panel) = state.curve_fit.lock() {
+                        draw_curve_fit(ui, params, sample_rate, &mut panel);
+                    }
                 });
             },
         );
@@ -288,8 +827,18 @@ impl Editor for EQPluginEditor {
         self.is_open
     }
 
+    // Called on the main thread, where the host can be told about a new latency
+    fn idle(&mut self) {
+        self.state.latency_reporter.report(&self.state.params);
+    }
+
     fn close(&mut self) {
         self.is_open = false;
+        // Solo isn't saved and can't be seen with the window closed, so it ends with the window
+        if let Ok(mut latched_solo) = self.state.latched_solo.lock() {
+            *latched_solo = None;
+        }
+        self.state.params.set_solo(None);
     }
 }
 
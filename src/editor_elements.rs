@@ -37,6 +37,40 @@ fn draw_db_line(ui: &Ui, db: f32, graph_width: f32, graph_height: f32, db_px_ste
     );
 }
 
+/// Another curve on a graph, at the position returned by `draw_eq_graph`
+#[allow(clippy::too_many_arguments)]
+pub fn draw_eq_curve<F: Fn(usize) -> f32>(
+    ui: &Ui,
+    pos: [f32; 2],
+    size: [f32; 2],
+    db_px_step: f32,
+    thinkness: f32,
+    color: [f32; 4],
+    length: usize,
+    value_fn: F,
+) {
+    let [cx, cy] = pos;
+    let scale = (size[0] as f32 / length as f32) as f32;
+    let v_center = size[1] / 2.0;
+    let mut last = value_fn(0) * db_px_step;
+    let draw_list = ui.get_window_draw_list();
+    for i in 0..length {
+        let fi = i as f32;
+        let next = value_fn(i) * db_px_step;
+        let x_ofs = if (next - last).abs() < 1.0 { 1.0 } else { 0.0 };
+        let p1 = [cx + fi * scale, cy + v_center + last];
+        let p2 = [cx + fi * scale + x_ofs, cy + v_center + next];
+        if !(p1[1] < 0.0 || p1[1] > size[1] || p2[1] < 0.0 || p2[1] > size[1]) {
+            draw_list
+                .add_line(p1, p2, color)
+                .thickness(thinkness)
+                .build();
+        }
+        last = next;
+    }
+}
+
+/// Returns the position to draw more curves at with `draw_eq_curve`
 pub fn draw_eq_graph<F: Fn(usize) -> f32>(
     ui: &Ui,
     id: &ImStr,
@@ -45,7 +79,7 @@ pub fn draw_eq_graph<F: Fn(usize) -> f32>(
     thinkness: f32,
     length: usize,
     value_fn: F,
-) {
+) -> [f32; 2] {
     let [cx, mut cy] = ui.cursor_screen_pos();
     cy += 4.0; //TODO off by a bit
     ui.invisible_button(id, size);
@@ -55,27 +89,17 @@ pub fn draw_eq_graph<F: Fn(usize) -> f32>(
     } else {
         ui.style_color(StyleColor::PlotLines)
     };
-    let scale = (size[0] as f32 / length as f32) as f32;
     color[3] = (color[3] * 0.9).min(1.0).max(0.0);
-    let v_center = size[1] / 2.0;
-    let mut last = value_fn(0) * db_px_step;
-    {
-        let draw_list = ui.get_window_draw_list();
-        for i in 0..length {
-            let fi = i as f32;
-            let next = value_fn(i) * db_px_step;
-            let x_ofs = if (next - last).abs() < 1.0 { 1.0 } else { 0.0 };
-            let p1 = [cx + fi * scale, cy + v_center + last];
-            let p2 = [cx + fi * scale + x_ofs, cy + v_center + next];
-            if !(p1[1] < 0.0 || p1[1] > size[1] || p2[1] < 0.0 || p2[1] > size[1]) {
-                draw_list
-                    .add_line(p1, p2, color)
-                    .thickness(thinkness)
-                    .build();
-            }
-            last = next;
-        }
-    }
+    draw_eq_curve(
+        ui,
+        [cx, cy],
+        size,
+        db_px_step,
+        thinkness,
+        color,
+        length,
+        value_fn,
+    );
 
     for n in [
         20, 30, 50, 100, 200, 300, 500, 1000, 2000, 3000, 5000, 10000, 20000,
@@ -96,4 +120,5 @@ pub fn draw_eq_graph<F: Fn(usize) -> f32>(
     for db in [-12.0, -6.0, 0.0, 6.0, 12.0].iter() {
         draw_db_line(ui, *db, size[0], size[1], db_px_step);
     }
+    [cx, cy]
 }
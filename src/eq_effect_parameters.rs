@@ -1,10 +1,18 @@
-use crate::FILTER_POLE_COUNT;
+use crate::parameter_registry::{BandField, GlobalField, ParameterId, ParameterRegistry};
+use crate::{ab_comparison::ABComparison, atomic_bool::AtomicBool};
+use crate::{programs::factory_programs, state::EQState};
+use crate::{FILTER_COUNT, FILTER_POLE_COUNT, FIR_LENGTHS, MAX_FILTER_COUNT, MIN_FILTER_COUNT};
 
-use super::parameter::Parameter;
+use super::parameter::{Mapping, Parameter};
+use crate::linear_phase::LatencyReporter;
+use crate::parameter_text::*;
+use crate::seqlock::SeqLock;
 
 use core::fmt;
 
-#[derive(PartialEq, Debug, Clone, Copy)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
 pub enum BandKind {
     Bell,
     LowPass,
@@ -17,6 +25,8 @@ pub enum BandKind {
 }
 
 impl BandKind {
+    pub const COUNT: usize = 8;
+
     pub fn from_u8(value: u8) -> BandKind {
         match value {
             0 => BandKind::Bell,
@@ -30,6 +40,14 @@ impl BandKind {
             _ => BandKind::LowPass,
         }
     }
+
+    /// Kinds whose shape depends on the gain, the only ones dynamics can change
+    pub fn has_gain(&self) -> bool {
+        match self {
+            BandKind::Bell | BandKind::LowShelf | BandKind::HighShelf | BandKind::BandPass => true,
+            BandKind::LowPass | BandKind::HighPass | BandKind::Notch | BandKind::AllPass => false,
+        }
+    }
 }
 
 impl fmt::Display for BandKind {
@@ -38,13 +56,15 @@ impl fmt::Display for BandKind {
     }
 }
 
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
 pub enum BandMode {
     Butterworth,
     LinkwitzRiley,
 }
 
 impl BandMode {
+    pub const COUNT: usize = 2;
+
     pub fn from_u8(value: u8) -> BandMode {
         match value {
             0 => BandMode::Butterworth,
@@ -60,6 +80,105 @@ impl fmt::Display for BandMode {
     }
 }
 
+/// Channels a band changes. Mid and side bands run between an M/S encode and decode.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum BandChannel {
+    Stereo,
+    Left,
+    Right,
+    Mid,
+    Side,
+}
+
+impl BandChannel {
+    pub const COUNT: usize = 5;
+
+    pub fn from_u8(value: u8) -> BandChannel {
+        match value {
+            0 => BandChannel::Stereo,
+            1 => BandChannel::Left,
+            2 => BandChannel::Right,
+            3 => BandChannel::Mid,
+            4 => BandChannel::Side,
+            _ => BandChannel::Stereo,
+        }
+    }
+}
+
+impl Default for BandChannel {
+    fn default() -> Self {
+        BandChannel::Stereo
+    }
+}
+
+impl fmt::Display for BandChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// What the detector of a dynamic band listens to
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum BandKey {
+    /// The audio going into the band
+    Internal,
+    /// The sidechain input
+    External,
+}
+
+impl BandKey {
+    pub const COUNT: usize = 2;
+
+    pub fn from_u8(value: u8) -> BandKey {
+        match value {
+            0 => BandKey::Internal,
+            1 => BandKey::External,
+            _ => BandKey::Internal,
+        }
+    }
+}
+
+impl Default for BandKey {
+    fn default() -> Self {
+        BandKey::Internal
+    }
+}
+
+impl fmt::Display for BandKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// How the output is matched to the loudness of the input
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum AutoGain {
+    Off,
+    /// Estimated from the curve whenever a band changes
+    Curve,
+    /// Measured from the audio
+    Live,
+}
+
+impl AutoGain {
+    pub const COUNT: usize = 3;
+
+    pub fn from_u8(value: u8) -> AutoGain {
+        match value {
+            0 => AutoGain::Off,
+            1 => AutoGain::Curve,
+            2 => AutoGain::Live,
+            _ => AutoGain::Off,
+        }
+    }
+}
+
+impl fmt::Display for AutoGain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 pub struct BandParameters {
     pub kind: Parameter,
     pub freq: Parameter,
@@ -67,6 +186,19 @@ pub struct BandParameters {
     pub bw: Parameter,
     pub slope: Parameter,
     pub mode: Parameter,
+    /// Off skips the band, so it works for every kind, unlike setting the gain to 0
+    pub enabled: Parameter,
+    pub channel: Parameter,
+    /// Level of the band's frequency range where the gain starts to move
+    pub threshold: Parameter,
+    pub ratio: Parameter,
+    pub attack: Parameter,
+    pub release: Parameter,
+    /// Most the gain moves, down when negative and up when positive. 0 makes the band static.
+    pub range: Parameter,
+    pub key: Parameter,
+    /// Every field is written through this, so the DSP can read the band as one snapshot
+    pub lock: Arc<SeqLock>,
 }
 
 impl BandParameters {
@@ -82,135 +214,398 @@ impl BandParameters {
         self.mode.get().floor()
     }
 
-    pub fn dsp_update(&self) -> bool {
-        if self.kind.dsp_update() {
-            true
-        } else if self.freq.dsp_update() {
-            true
-        } else if self.gain.dsp_update() {
-            true
-        } else if self.bw.dsp_update() {
-            true
-        } else if self.slope.dsp_update() {
-            true
-        } else if self.mode.dsp_update() {
-            true
-        } else {
-            false
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get() >= 0.5
+    }
+
+    pub fn get_channel(&self) -> BandChannel {
+        BandChannel::from_u8(self.channel.get() as u8)
+    }
+
+    pub fn get_key(&self) -> BandKey {
+        BandKey::from_u8(self.key.get() as u8)
+    }
+
+    pub fn get(&self, field: BandField) -> &Parameter {
+        match field {
+            BandField::Kind => &self.kind,
+            BandField::Freq => &self.freq,
+            BandField::Gain => &self.gain,
+            BandField::Bw => &self.bw,
+            BandField::Slope => &self.slope,
+            BandField::Mode => &self.mode,
+            BandField::Enabled => &self.enabled,
+            BandField::Channel => &self.channel,
+            BandField::Threshold => &self.threshold,
+            BandField::Ratio => &self.ratio,
+            BandField::Attack => &self.attack,
+            BandField::Release => &self.release,
+            BandField::Range => &self.range,
+            BandField::Key => &self.key,
         }
     }
+
+    /// In the order of the `BandField` variants
+    pub fn parameters(&self) -> [&Parameter; 14] {
+        [
+            &self.kind,
+            &self.freq,
+            &self.gain,
+            &self.bw,
+            &self.slope,
+            &self.mode,
+            &self.enabled,
+            &self.channel,
+            &self.threshold,
+            &self.ratio,
+            &self.attack,
+            &self.release,
+            &self.range,
+            &self.key,
+        ]
+    }
 }
 
+/// Value of `solo` when no band is soloed
+const NO_SOLO: usize = usize::MAX;
+
 pub struct EQEffectParameters {
     pub bands: Vec<Arc<BandParameters>>,
+    pub programs: Mutex<Vec<EQState>>,
+    pub current_program: AtomicUsize,
+    /// Number of `set_state` calls in progress, the DSP waits until there are none
+    pub applying_state: AtomicUsize,
+    /// Counts `set_state` calls, so a reader can tell one started while it was reading
+    pub state_generation: AtomicUsize,
+    pub ab: Mutex<ABComparison>,
+    pub registry: ParameterRegistry,
+    /// Time for freq, gain and bandwidth changes to glide to their new value
+    pub smoothing: Parameter,
+    /// Gain before the bands
+    pub input_trim: Parameter,
+    /// Gain after the bands, auto gain is added to this
+    pub output_gain: Parameter,
+    pub auto_gain: Parameter,
+    /// Runs the curve as one FIR filter, with no phase shift but some latency
+    pub linear_phase: Parameter,
+    /// Index into `FIR_LENGTHS`
+    pub fir_length: Parameter,
+    /// Set by the host, not saved with the state
+    pub bypass: AtomicBool,
+    /// Band heard on its own, set by the editor and not saved with the state
+    solo: AtomicUsize,
+    /// Set by the plugin once it knows its host, so latency changes made through
+    /// the host's parameter, program and chunk calls are reported as they happen
+    pub latency_reporter: Mutex<Option<Arc<LatencyReporter>>>,
 }
 
-use std::{ops::Index, sync::Arc};
+use std::{
+    ops::Index,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
 impl Index<usize> for EQEffectParameters {
     type Output = Parameter;
     fn index(&self, i: usize) -> &Self::Output {
-        match i {
-            0 => &self.bands[0].kind,
-            1 => &self.bands[0].freq,
-            2 => &self.bands[0].gain,
-            3 => &self.bands[0].bw,
-            4 => &self.bands[0].slope,
-            5 => &self.bands[0].mode,
-            6 => &self.bands[1].kind,
-            7 => &self.bands[1].freq,
-            8 => &self.bands[1].gain,
-            9 => &self.bands[1].bw,
-            10 => &self.bands[1].slope,
-            11 => &self.bands[1].mode,
-            12 => &self.bands[2].kind,
-            13 => &self.bands[2].freq,
-            14 => &self.bands[2].gain,
-            15 => &self.bands[2].bw,
-            16 => &self.bands[2].slope,
-            17 => &self.bands[2].mode,
-            18 => &self.bands[3].kind,
-            19 => &self.bands[3].freq,
-            20 => &self.bands[3].gain,
-            21 => &self.bands[3].bw,
-            22 => &self.bands[3].slope,
-            23 => &self.bands[3].mode,
-            _ => &self.bands[3].kind,
+        match self.get(i) {
+            Some(parameter) => parameter,
+            None => panic!(
+                "parameter index {} is out of range or an empty slot, there are {}",
+                i,
+                self.len()
+            ),
         }
     }
 }
 
 impl EQEffectParameters {
+    /// `band_count` is clamped to the supported range
+    pub fn new(band_count: usize) -> EQEffectParameters {
+        let band_count = band_count.max(MIN_FILTER_COUNT).min(MAX_FILTER_COUNT);
+        EQEffectParameters {
+            bands: (0..band_count)
+                .map(|i| Arc::new(new_band_pram_set(i + 1)))
+                .collect::<Vec<Arc<BandParameters>>>(),
+            programs: Mutex::new(factory_programs(band_count)),
+            current_program: AtomicUsize::new(0),
+            applying_state: AtomicUsize::new(0),
+            state_generation: AtomicUsize::new(0),
+            ab: Mutex::new(ABComparison::default()),
+            registry: ParameterRegistry::new(band_count),
+            smoothing: Parameter::new(
+                "Smoothing",
+                "",
+                20.0,
+                1.0,
+                1000.0,
+                display_time,
+                parse_time,
+                Mapping::Logarithmic,
+            ),
+            input_trim: Parameter::new(
+                "Input Trim",
+                "",
+                0.0,
+                -24.0,
+                24.0,
+                display_gain,
+                parse_gain,
+                Mapping::Bipolar(2.0),
+            ),
+            output_gain: Parameter::new(
+                "Output Gain",
+                "",
+                0.0,
+                -24.0,
+                24.0,
+                display_gain,
+                parse_gain,
+                Mapping::Bipolar(2.0),
+            ),
+            auto_gain: Parameter::new_stepped(
+                "Auto Gain",
+                "",
+                0.0,
+                0.0,
+                auto_gain_choices(),
+                parse_auto_gain,
+            ),
+            linear_phase: Parameter::new_stepped(
+                "Linear Phase",
+                "",
+                0.0,
+                0.0,
+                switch_choices(),
+                parse_switch,
+            ),
+            fir_length: Parameter::new_stepped(
+                "FIR Length",
+                "taps",
+                1.0,
+                0.0,
+                fir_length_choices(),
+                parse_fir_length,
+            ),
+            bypass: AtomicBool::new(false),
+            solo: AtomicUsize::new(NO_SOLO),
+            latency_reporter: Mutex::new(None),
+        }
+    }
+
+    pub fn get_solo(&self) -> Option<usize> {
+        match self.solo.load(Ordering::Relaxed) {
+            NO_SOLO => None,
+            band => Some(band),
+        }
+    }
+
+    pub fn set_solo(&self, band: Option<usize>) {
+        self.solo.store(band.unwrap_or(NO_SOLO), Ordering::Relaxed);
+    }
+
+    pub fn get_auto_gain(&self) -> AutoGain {
+        AutoGain::from_u8(self.auto_gain.get() as u8)
+    }
+
+    pub fn is_linear_phase(&self) -> bool {
+        self.linear_phase.get() >= 0.5
+    }
+
+    pub fn get_fir_length(&self) -> usize {
+        FIR_LENGTHS[(self.fir_length.get() as usize).min(FIR_LENGTHS.len() - 1)]
+    }
+
+    /// Tells the host if the latency changed. `from_any_thread` is for calls the host
+    /// can make from the audio thread, which never wait for the lock or report from there.
+    pub fn report_latency(&self, from_any_thread: bool) {
+        if let Ok(reporter) = self.latency_reporter.try_lock() {
+            match reporter.as_ref() {
+                Some(reporter) if from_any_thread => reporter.report_from_host_ui(self),
+                Some(reporter) => reporter.report(self),
+                None => (),
+            }
+        }
+    }
+
+    /// Number of host parameter indices, including the empty slots of missing bands
     pub fn len(&self) -> usize {
-        23
+        self.registry.len()
+    }
+
+    /// `None` past the end and for the empty slots of missing bands
+    pub fn get(&self, index: usize) -> Option<&Parameter> {
+        self.registry.get(index).map(|id| self.parameter(id))
+    }
+
+    pub fn parameter(&self, id: ParameterId) -> &Parameter {
+        match id {
+            ParameterId::Band { band, field } => self.bands[band].get(field),
+            ParameterId::Global(GlobalField::Smoothing) => &self.smoothing,
+            ParameterId::Global(GlobalField::InputTrim) => &self.input_trim,
+            ParameterId::Global(GlobalField::OutputGain) => &self.output_gain,
+            ParameterId::Global(GlobalField::AutoGain) => &self.auto_gain,
+            ParameterId::Global(GlobalField::LinearPhase) => &self.linear_phase,
+            ParameterId::Global(GlobalField::FirLength) => &self.fir_length,
+        }
     }
 }
 
+/// `n` is one based, and only depends on the band's position so names stay
+/// the same when more bands are added.
 fn new_band_pram_set(n: usize) -> BandParameters {
+    let lock = Arc::new(SeqLock::new());
     BandParameters {
-        kind: Parameter::new(
+        kind: Parameter::new_stepped(
             &format!("Band {} Type", n),
+            "",
             0.0,
             0.0,
-            10.0,
-            |x| BandKind::from_u8(x as u8).to_string(),
-            |x| x,
-            |x| x,
-        ),
+            kind_choices(),
+            parse_kind,
+        )
+        .with_lock(&lock),
         freq: Parameter::new(
-            &format!("Band {} hz", n),
+            &format!("Band {} Freq", n),
+            "",
             1000.0,
             20.0,
             20000.0,
-            |x| format!("hz {:.2}", x),
-            |x| x.powf(2.0),
-            |x| x.powf(0.5),
-        ),
+            display_freq,
+            parse_freq,
+            Mapping::Logarithmic,
+        )
+        .with_lock(&lock),
         gain: Parameter::new(
-            &format!("Band {} dB", n),
+            &format!("Band {} Gain", n),
+            "",
             0.0,
             -24.0,
             24.0,
-            |x| format!("dB {:.2}", x),
-            |x| x,
-            |x| x,
-        ),
+            display_gain,
+            parse_gain,
+            Mapping::Bipolar(2.0),
+        )
+        .with_lock(&lock),
         bw: Parameter::new(
-            &format!("Band {} BW", n),
+            &format!("Band {} Q", n),
+            "",
             1.0,
             0.1,
             24.0,
-            |x| format!("BW {:.2}", x),
-            |x| x,
-            |x| x,
-        ),
-        slope: Parameter::new(
+            display_bw,
+            parse_bw,
+            Mapping::Logarithmic,
+        )
+        .with_lock(&lock),
+        slope: Parameter::new_stepped(
             &format!("Band {} Slope", n),
+            "",
             1.0,
             1.0,
-            FILTER_POLE_COUNT as f64,
-            |x| format!("Slope {:.2}", x),
-            |x| x,
-            |x| x,
-        ),
-        mode: Parameter::new(
-            &format!("Band {} mode", n),
+            slope_choices(FILTER_POLE_COUNT),
+            parse_slope,
+        )
+        .with_lock(&lock),
+        mode: Parameter::new_stepped(
+            &format!("Band {} Mode", n),
+            "",
             0.0,
             0.0,
-            2.0,
-            |x| BandMode::from_u8(x as u8).to_string(),
-            |x| x,
-            |x| x,
-        ),
+            mode_choices(),
+            parse_mode,
+        )
+        .with_lock(&lock),
+        enabled: Parameter::new_stepped(
+            &format!("Band {} On", n),
+            "",
+            1.0,
+            0.0,
+            switch_choices(),
+            parse_switch,
+        )
+        .with_lock(&lock),
+        channel: Parameter::new_stepped(
+            &format!("Band {} Channel", n),
+            "",
+            0.0,
+            0.0,
+            channel_choices(),
+            parse_channel,
+        )
+        .with_lock(&lock),
+        threshold: Parameter::new(
+            &format!("Band {} Threshold", n),
+            "",
+            -20.0,
+            -60.0,
+            0.0,
+            display_gain,
+            parse_gain,
+            Mapping::Linear,
+        )
+        .with_lock(&lock),
+        ratio: Parameter::new(
+            &format!("Band {} Ratio", n),
+            "",
+            1.0,
+            1.0,
+            20.0,
+            display_ratio,
+            parse_ratio,
+            Mapping::Logarithmic,
+        )
+        .with_lock(&lock),
+        attack: Parameter::new(
+            &format!("Band {} Attack", n),
+            "",
+            10.0,
+            0.1,
+            500.0,
+            display_time,
+            parse_time,
+            Mapping::Logarithmic,
+        )
+        .with_lock(&lock),
+        release: Parameter::new(
+            &format!("Band {} Release", n),
+            "",
+            100.0,
+            5.0,
+            5000.0,
+            display_time,
+            parse_time,
+            Mapping::Logarithmic,
+        )
+        .with_lock(&lock),
+        range: Parameter::new(
+            &format!("Band {} Range", n),
+            "",
+            0.0,
+            -24.0,
+            24.0,
+            display_gain,
+            parse_gain,
+            Mapping::Bipolar(2.0),
+        )
+        .with_lock(&lock),
+        key: Parameter::new_stepped(
+            &format!("Band {} Key", n),
+            "",
+            0.0,
+            0.0,
+            key_choices(),
+            parse_key,
+        )
+        .with_lock(&lock),
+        lock,
     }
 }
 
 impl Default for EQEffectParameters {
     fn default() -> EQEffectParameters {
-        EQEffectParameters {
-            bands: (0..4)
-                .map(|_| Arc::new(new_band_pram_set(1)))
-                .collect::<Vec<Arc<BandParameters>>>(),
-        }
+        EQEffectParameters::new(FILTER_COUNT)
     }
 }
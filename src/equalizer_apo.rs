@@ -0,0 +1,540 @@
+//! Equalizer APO / AutoEQ `ParametricEQ.txt` filter text
+//!
+//! Lines look like `Filter 1: ON PK Fc 105 Hz Gain -3.2 dB Q 0.70`, with an
+//! optional `Preamp: -6.2 dB` line.
+
+use crate::dynamics::is_dynamic;
+use crate::eq_effect_parameters::{BandChannel, BandKind, BandMode, EQEffectParameters};
+use crate::state::{BandState, EQState, STATE_VERSION};
+use crate::units::Units;
+
+/// Q used by Equalizer APO when a filter doesn't give one
+const DEFAULT_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+pub struct ApoImport {
+    pub bands: Vec<BandState>,
+    /// `None` when the text has no preamp line
+    pub preamp: Option<f64>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Default)]
+struct FilterValues {
+    fc: Option<f64>,
+    gain: Option<f64>,
+    q: Option<f64>,
+    bw: Option<f64>,
+}
+
+fn parse_number(token: Option<&&str>) -> Option<f64> {
+    token?.trim_end_matches(&[',', ';'][..]).parse().ok()
+}
+
+fn parse_values(tokens: &[&str]) -> FilterValues {
+    let mut values = FilterValues::default();
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i].to_ascii_lowercase().as_str() {
+            "fc" => values.fc = parse_number(tokens.get(i + 1)),
+            "gain" => values.gain = parse_number(tokens.get(i + 1)),
+            "q" => values.q = parse_number(tokens.get(i + 1)),
+            // BW Oct 1.0
+            "bw" if tokens.get(i + 1).map(|t| t.eq_ignore_ascii_case("oct")) == Some(true) => {
+                values.bw = parse_number(tokens.get(i + 2));
+                i += 1;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    values
+}
+
+/// Maps one filter onto a band. `kind_tokens` are the filter type and the optional
+/// shelf slope that follows it, like `LS 6dB`.
+fn filter_to_band(kind_tokens: &[&str], values: &FilterValues) -> Result<BandState, String> {
+    let filter_type = kind_tokens[0].to_ascii_uppercase();
+    let shelf_slope = match kind_tokens.get(1).map(|t| t.to_ascii_lowercase()) {
+        Some(t) if t == "6db" => 1.0,
+        _ => 2.0,
+    };
+    let (kind, slope, needs_gain) = match filter_type.as_str() {
+        "PK" | "PEQ" => (BandKind::Bell, 1.0, true),
+        "LS" | "LSC" | "LSQ" => (BandKind::LowShelf, shelf_slope, true),
+        "HS" | "HSC" | "HSQ" => (BandKind::HighShelf, shelf_slope, true),
+        "LP" | "LPQ" => (BandKind::LowPass, 2.0, false),
+        "HP" | "HPQ" => (BandKind::HighPass, 2.0, false),
+        "NO" => (BandKind::Notch, 1.0, false),
+        "BP" => (BandKind::BandPass, 1.0, false),
+        "AP" => (BandKind::AllPass, 2.0, false),
+        _ => return Err(format!("filter type {} is not supported", filter_type)),
+    };
+    let freq = values
+        .fc
+        .ok_or_else(|| format!("{} filter has no Fc", filter_type))?;
+    let gain = match values.gain {
+        Some(gain) => gain,
+        None if needs_gain => return Err(format!("{} filter has no Gain", filter_type)),
+        None => 0.0,
+    };
+    let bw = match (values.bw, values.q) {
+        (Some(bw), _) => bw,
+        (None, Some(q)) if q > 0.0 => q.q_to_bw(freq, 0.0),
+        (None, Some(q)) => return Err(format!("Q {} is not positive", q)),
+        (None, None) => DEFAULT_Q.q_to_bw(freq, 0.0),
+    };
+    Ok(BandState {
+        kind: kind as u8 as f64,
+        freq,
+        gain,
+        bw,
+        slope,
+        mode: BandMode::Butterworth as u8 as f64,
+        ..BandState::default()
+    })
+}
+
+/// Parses the text, warning about every line that can't be used instead of failing
+pub fn parse(text: &str, band_count: usize) -> ApoImport {
+    let mut import = ApoImport {
+        bands: Vec::new(),
+        preamp: None,
+        warnings: Vec::new(),
+    };
+    for (line_i, line) in text.lines().enumerate() {
+        let line_n = line_i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (label, rest) = match line.find(':') {
+            Some(i) => (line[..i].trim(), &line[i + 1..]),
+            None => {
+                import
+                    .warnings
+                    .push(format!("Line {}: not a filter line, skipped", line_n));
+                continue;
+            }
+        };
+        let tokens = rest.split_whitespace().collect::<Vec<&str>>();
+        if label.eq_ignore_ascii_case("preamp") {
+            match parse_number(tokens.first()) {
+                Some(preamp) => import.preamp = Some(import.preamp.unwrap_or(0.0) + preamp),
+                None => import
+                    .warnings
+                    .push(format!("Line {}: could not read the preamp", line_n)),
+            }
+            continue;
+        }
+        if !label.to_ascii_lowercase().starts_with("filter") {
+            import.warnings.push(format!(
+                "Line {}: \"{}\" is not supported, skipped",
+                line_n, label
+            ));
+            continue;
+        }
+        // Filters that are OFF are kept as bands that are switched off
+        let enabled = match tokens.first().map(|t| t.to_ascii_uppercase()) {
+            Some(t) if t == "ON" => 1.0,
+            Some(t) if t == "OFF" => 0.0,
+            _ => {
+                import
+                    .warnings
+                    .push(format!("Line {}: filter is not ON or OFF, skipped", line_n));
+                continue;
+            }
+        };
+        if tokens.len() < 2 {
+            import
+                .warnings
+                .push(format!("Line {}: filter has no type, skipped", line_n));
+            continue;
+        }
+        match filter_to_band(&tokens[1..], &parse_values(&tokens[2..])) {
+            Ok(_) if import.bands.len() == band_count => import.warnings.push(format!(
+                "Line {}: only {} bands are available, skipped",
+                line_n, band_count
+            )),
+            Ok(band) => import.bands.push(BandState { enabled, ..band }),
+            Err(e) => import
+                .warnings
+                .push(format!("Line {}: {}, skipped", line_n, e)),
+        }
+    }
+    import
+}
+
+/// Replaces every band with the imported filters and sets the input trim to the preamp,
+/// if there is one, returning the warnings
+pub fn import(params: &EQEffectParameters, text: &str) -> Vec<String> {
+    let mut import = parse(text, params.bands.len());
+    if let Some(preamp) = import.preamp {
+        let trim = &params.input_trim;
+        if preamp < trim.min || preamp > trim.max {
+            import.warnings.push(format!(
+                "Preamp of {:.1} dB is outside {}..{} and was clamped",
+                preamp, trim.min, trim.max
+            ));
+        }
+        trim.set(preamp);
+    }
+    for (i, (band, state)) in params.bands.iter().zip(import.bands.iter()).enumerate() {
+        let checks = [
+            (&band.freq, state.freq, "Fc"),
+            (&band.gain, state.gain, "Gain"),
+            (&band.bw, state.bw, "bandwidth"),
+        ];
+        for (parameter, value, name) in checks.iter() {
+            if *value < parameter.min || *value > parameter.max {
+                import.warnings.push(format!(
+                    "Filter {}: {} of {:.2} is outside {}..{} and was clamped",
+                    i + 1,
+                    name,
+                    value,
+                    parameter.min,
+                    parameter.max
+                ));
+            }
+        }
+    }
+    params.set_state(&EQState {
+        version: STATE_VERSION,
+        name: String::new(),
+        bands: import.bands,
+        globals: None,
+    });
+    import.warnings
+}
+
+pub struct ApoExport {
+    pub text: String,
+    pub warnings: Vec<String>,
+}
+
+/// The closest Equalizer APO filter for a band, with a warning when it isn't exact.
+/// `None` for bands that never change the response.
+fn band_to_filter(band: &BandState) -> Option<(String, Option<String>)> {
+    let kind = BandKind::from_u8(band.kind as u8);
+    let slope = band.slope as u32;
+    let q = band.bw.bw_to_q(band.freq, 0.0);
+    let fc = format!("Fc {:.1} Hz", band.freq);
+    let gain = format!("Gain {:.1} dB", band.gain);
+
+    let mut inexact = Vec::new();
+    if BandMode::from_u8(band.mode as u8) == BandMode::LinkwitzRiley {
+        inexact.push("Linkwitz-Riley mode is exported as Butterworth".to_string());
+    }
+    let channel = BandChannel::from_u8(band.channel as u8);
+    if channel != BandChannel::Stereo {
+        inexact.push(format!("{} channel is exported for both channels", channel));
+    }
+    // A flat dynamic band still moves, so it is exported to carry the warning
+    let dynamic = is_dynamic(band);
+    if dynamic {
+        inexact.push("dynamics are dropped, only the static gain is exported".to_string());
+    }
+    // Equalizer APO's notch and band pass have no gain
+    if matches!(kind, BandKind::Notch | BandKind::BandPass) && band.gain != 0.0 {
+        inexact.push(format!("gain of {:.1} dB is dropped", band.gain));
+    }
+    // Shelves, high pass and low pass are one biquad (12dB/oct) in Equalizer APO
+    let mut expect_slope = |exported: u32| {
+        if slope != exported {
+            inexact.push(format!(
+                "{}dB/oct is exported as {}dB/oct",
+                slope * 6,
+                exported * 6
+            ));
+        }
+    };
+
+    let filter = match kind {
+        BandKind::Bell if band.gain == 0.0 && !dynamic => return None,
+        BandKind::LowShelf | BandKind::HighShelf if band.gain == 0.0 && !dynamic => return None,
+        BandKind::Bell => format!("PK {} {} Q {:.2}", fc, gain, q),
+        BandKind::LowShelf | BandKind::HighShelf => {
+            let name = if kind == BandKind::LowShelf {
+                "LS"
+            } else {
+                "HS"
+            };
+            if slope <= 1 {
+                if (band.bw - DEFAULT_Q.q_to_bw(band.freq, 0.0)).abs() > 0.01 {
+                    inexact.push("6dB shelves have no bandwidth, the default is used".to_string());
+                }
+                format!("{} 6dB {} {}", name, fc, gain)
+            } else {
+                expect_slope(2);
+                format!("{}C {} {} Q {:.2}", name, fc, gain, q)
+            }
+        }
+        BandKind::LowPass => {
+            expect_slope(2);
+            format!("LPQ {} Q {:.2}", fc, q)
+        }
+        BandKind::HighPass => {
+            expect_slope(2);
+            format!("HPQ {} Q {:.2}", fc, q)
+        }
+        BandKind::Notch => format!("NO {} Q {:.2}", fc, q),
+        BandKind::BandPass => format!("BP {} Q {:.2}", fc, q),
+        BandKind::AllPass => {
+            expect_slope(2);
+            format!("AP {} Q {:.2}", fc, q)
+        }
+    };
+    let warning = if inexact.is_empty() {
+        None
+    } else {
+        Some(inexact.join(", "))
+    };
+    Some((filter, warning))
+}
+
+/// Equalizer APO filter text, which REW can also import
+pub fn export(params: &EQEffectParameters) -> ApoExport {
+    let mut export = ApoExport {
+        text: String::new(),
+        warnings: Vec::new(),
+    };
+    let preamp = params.input_trim.get();
+    if preamp != 0.0 {
+        export.text.push_str(&format!("Preamp: {:.1} dB\n", preamp));
+    }
+    let mut filter_n = 0;
+    for (i, band) in params.get_state().bands.iter().enumerate() {
+        if let Some((filter, warning)) = band_to_filter(band) {
+            filter_n += 1;
+            let on = if band.enabled >= 0.5 { "ON" } else { "OFF" };
+            export
+                .text
+                .push_str(&format!("Filter {}: {} {}\n", filter_n, on, filter));
+            if let Some(warning) = warning {
+                export
+                    .warnings
+                    .push(format!("Band {} is not exact: {}", i + 1, warning));
+            }
+        }
+    }
+    export
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn filter_lines_become_bands() {
+        let text = "Preamp: -6.2 dB\n\
+                    Filter 1: ON PK Fc 105 Hz Gain -3.2 dB Q 0.70\n\
+                    Filter 2: ON LSC Fc 80 Hz Gain 4.5 dB Q 0.71\n\
+                    Filter 3: OFF HS 6dB Fc 8000 Hz Gain 2 dB\n\
+                    Filter 4: ON HP Fc 30 Hz\n\
+                    Filter 5: ON PK Fc 1000 Hz Gain 1 dB BW Oct 2.0\n";
+        let import = parse(text, 8);
+        assert!(import.warnings.is_empty(), "{:?}", import.warnings);
+        assert_eq!(import.preamp, Some(-6.2));
+        assert_eq!(import.bands.len(), 5);
+
+        let bell = &import.bands[0];
+        assert_eq!(bell.kind, BandKind::Bell as u8 as f64);
+        assert_eq!((bell.freq, bell.gain), (105.0, -3.2));
+        assert_close(bell.bw, 0.7.q_to_bw(bell.freq, 0.0));
+        assert_eq!(bell.enabled, 1.0);
+
+        let shelf = &import.bands[1];
+        assert_eq!(shelf.kind, BandKind::LowShelf as u8 as f64);
+        assert_eq!(shelf.slope, 2.0);
+
+        let off = &import.bands[2];
+        assert_eq!(off.kind, BandKind::HighShelf as u8 as f64);
+        assert_eq!((off.slope, off.gain, off.enabled), (1.0, 2.0, 0.0));
+
+        // Without a Q, Equalizer APO uses a Butterworth Q
+        let high_pass = &import.bands[3];
+        assert_eq!(high_pass.kind, BandKind::HighPass as u8 as f64);
+        assert_close(high_pass.bw, DEFAULT_Q.q_to_bw(30.0, 0.0));
+
+        assert_eq!(import.bands[4].bw, 2.0);
+    }
+
+    #[test]
+    fn q_converts_to_octaves() {
+        assert_close(std::f64::consts::SQRT_2.q_to_bw(1000.0, 0.0), 1.0);
+        assert_close(DEFAULT_Q.q_to_bw(1000.0, 0.0), 1.8999);
+        for bw in [0.1, 0.5, 1.0, 2.5, 6.0].iter() {
+            assert_close(bw.bw_to_q(1000.0, 0.0).q_to_bw(1000.0, 0.0), *bw);
+        }
+    }
+
+    #[test]
+    fn preamp_lines_add_up() {
+        let import = parse("Preamp: -3 dB\nPreamp: -1.5 dB\n", 8);
+        assert_eq!(import.preamp, Some(-4.5));
+        assert_eq!(
+            parse("Filter 1: ON PK Fc 100 Hz Gain 1 dB\n", 8).preamp,
+            None
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let text = "# AutoEQ\n\n   \n  # indented comment\nFilter: ON PK Fc 100 Hz Gain 1 dB Q 1\n";
+        let import = parse(text, 8);
+        assert!(import.warnings.is_empty(), "{:?}", import.warnings);
+        assert_eq!(import.bands.len(), 1);
+    }
+
+    #[test]
+    fn unusable_lines_warn_and_are_skipped() {
+        let text = "Filter 1: ON XX Fc 100 Hz\n\
+                    Filter 2: ON PK Gain 1 dB\n\
+                    Filter 3: ON PK Fc 100 Hz\n\
+                    Filter 4: ON PK Fc 100 Hz Gain 1 dB Q 0\n\
+                    Filter 5: MAYBE PK Fc 100 Hz Gain 1 dB\n\
+                    Filter 6: ON\n\
+                    Channel: L\n\
+                    Preamp: loud\n\
+                    no colon here\n\
+                    Filter 7: ON PK Fc 200 Hz Gain 2 dB\n";
+        let import = parse(text, 8);
+        assert_eq!(import.bands.len(), 1);
+        assert_eq!(import.bands[0].freq, 200.0);
+        assert_eq!(import.preamp, None);
+        let lines = import
+            .warnings
+            .iter()
+            .map(|warning| warning.split(':').next().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            lines,
+            vec![
+                "Line 1", "Line 2", "Line 3", "Line 4", "Line 5", "Line 6", "Line 7", "Line 8",
+                "Line 9"
+            ]
+        );
+        assert!(import.warnings[0].contains("XX"));
+    }
+
+    #[test]
+    fn filters_past_the_band_count_are_skipped() {
+        let text = (1..=10)
+            .map(|i| format!("Filter {}: ON PK Fc {} Hz Gain 1 dB\n", i, i * 100))
+            .collect::<String>();
+        let import = parse(&text, 8);
+        assert_eq!(import.bands.len(), 8);
+        assert_eq!(import.warnings.len(), 2);
+    }
+
+    fn export_state(bands: &[BandState]) -> ApoExport {
+        let params = EQEffectParameters::default();
+        params.set_state(&EQState {
+            version: STATE_VERSION,
+            name: String::new(),
+            bands: bands.to_vec(),
+            globals: None,
+        });
+        export(&params)
+    }
+
+    fn band(kind: BandKind, freq: f64, gain: f64, bw: f64, slope: f64) -> BandState {
+        BandState {
+            kind: kind as u8 as f64,
+            freq,
+            gain,
+            bw,
+            slope,
+            ..BandState::default()
+        }
+    }
+
+    #[test]
+    fn export_imports_back_to_the_same_bands() {
+        let bands = vec![
+            band(BandKind::Bell, 105.0, -3.2, 1.5, 1.0),
+            band(BandKind::LowShelf, 80.0, 4.5, 1.0, 2.0),
+            // 6dB shelves have no Q in Equalizer APO, so they come back with the default one
+            band(
+                BandKind::HighShelf,
+                8000.0,
+                2.0,
+                DEFAULT_Q.q_to_bw(8000.0, 0.0),
+                1.0,
+            ),
+            band(BandKind::HighPass, 30.0, 0.0, 1.0, 2.0),
+            band(BandKind::LowPass, 18000.0, 0.0, 0.8, 2.0),
+            BandState {
+                enabled: 0.0,
+                ..band(BandKind::Notch, 60.0, 0.0, 0.3, 1.0)
+            },
+        ];
+        let export = export_state(&bands);
+        assert!(export.warnings.is_empty(), "{:?}", export.warnings);
+
+        let params = EQEffectParameters::default();
+        params.input_trim.set(3.0);
+        let warnings = import(&params, &export.text);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        // Exported with no preamp line, so the trim is kept
+        assert_eq!(params.input_trim.get(), 3.0);
+        let state = params.get_state();
+        for (exported, imported) in bands.iter().zip(state.bands.iter()) {
+            assert_eq!(imported.kind, exported.kind);
+            assert_eq!(imported.slope, exported.slope);
+            assert_eq!(imported.enabled, exported.enabled);
+            assert!((imported.freq - exported.freq).abs() < 0.05);
+            assert!((imported.gain - exported.gain).abs() < 0.05);
+            // Q is written with two decimals
+            assert!((imported.bw - exported.bw).abs() < 0.01, "{}", exported.bw);
+        }
+        assert_eq!(state.bands[bands.len()], BandState::default());
+    }
+
+    #[test]
+    fn export_warns_about_what_it_drops() {
+        let dynamic = BandState {
+            range: -6.0,
+            ratio: 4.0,
+            ..band(BandKind::Bell, 1000.0, 0.0, 1.0, 1.0)
+        };
+        let export = export_state(&[
+            band(BandKind::Notch, 50.0, -6.0, 0.3, 1.0),
+            band(BandKind::BandPass, 500.0, 3.0, 1.0, 1.0),
+            dynamic,
+            band(BandKind::LowShelf, 100.0, 3.0, 0.5, 1.0),
+            // Flat and static, so it isn't exported at all
+            band(BandKind::Bell, 2000.0, 0.0, 1.0, 1.0),
+        ]);
+        assert_eq!(export.text.lines().count(), 4, "{}", export.text);
+        assert_eq!(export.warnings.len(), 4, "{:?}", export.warnings);
+        assert!(export.warnings[0].starts_with("Band 1") && export.warnings[0].contains("gain of"));
+        assert!(export.warnings[1].starts_with("Band 2") && export.warnings[1].contains("gain of"));
+        assert!(
+            export.warnings[2].starts_with("Band 3") && export.warnings[2].contains("dynamics")
+        );
+    }
+
+    #[test]
+    fn import_replaces_every_band_and_sets_the_trim() {
+        let params = EQEffectParameters::default();
+        params.bands[5].gain.set(9.0);
+        let warnings = import(
+            &params,
+            "Preamp: -2 dB\nFilter 1: ON PK Fc 300 Hz Gain 40 dB\n",
+        );
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert_eq!(params.input_trim.get(), -2.0);
+        assert_eq!(params.bands[0].freq.get(), 300.0);
+        assert_eq!(params.bands[0].gain.get(), params.bands[0].gain.max);
+        assert_eq!(params.bands[5].gain.get(), 0.0);
+    }
+}
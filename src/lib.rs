@@ -4,16 +4,16 @@
 Ideas:
     Vertical spectrogram like BlueCat and fl studio
         also fft spectrum option with peak detection (see mequalizer)
-    Dynamic bands (use band pass as trigger)
-    Solo selected band with band pass
+    DONE - Dynamic bands (use band pass as trigger)
+    DONE - Solo selected band with band pass
     Tilt style bands
         Initial done, maybe also do flat tilt
     Handles for moving only Vertical/Horizontal movement?
     Look at compensating for frequency warping
     DONE - Interpolate to avoid zipper effects when automating
-    mid/side/left/right percentages
+    DONE - mid/side/left/right (percentages?)
     text input
-    Eventually fir filters?
+    DONE - Eventually fir filters?
     Oversampling? - Probably not
     DONE - Raised cosine (2 shelf filters?)
     DONE - Look at svf https://cytomic.com/files/dsp/SvfLinearTrapOptimised2.pdf
@@ -29,10 +29,26 @@ static A: AllocDisabler = AllocDisabler;
 #[macro_use]
 extern crate vst;
 
+mod ab_comparison;
+mod auto_gain;
+mod curve_fit;
+mod dynamics;
 mod editor;
 pub mod editor_elements;
 mod eq_effect_parameters;
+mod equalizer_apo;
+mod linear_phase;
+mod migration;
 mod parameter;
+mod parameter_registry;
+mod parameter_text;
+mod preset_file;
+mod programs;
+mod seqlock;
+mod smoothing;
+mod solo;
+mod state;
+mod undo_history;
 pub mod units;
 
 mod atomic_bool;
@@ -43,19 +59,37 @@ use audio_filters::filter_band::FilterBandCoefficients;
 use audio_filters::filter_band_wide::WideFilterBandCoefficients;
 use audio_filters::linkwitz_riley_wide::{WideLinkwitzRileyBand, WideLinkwitzRileyCoefficients};
 
-use editor::{EQPluginEditor, EditorState};
-use eq_effect_parameters::{BandKind, BandParameters, EQEffectParameters};
-
+use auto_gain::LiveLoudness;
+use dynamics::BandDynamics;
+use editor::{CurveFitPanel, EQPluginEditor, EditorState, PresetBrowser};
+use eq_effect_parameters::{AutoGain, BandChannel, BandKind, BandParameters, EQEffectParameters};
+use linear_phase::{Convolver, KernelBuilder, LatencyReporter};
+use parameter_registry::{GlobalField, ParameterId};
+use seqlock::Snapshot;
+use smoothing::{BandSmoothing, Crossfade, GainSmoothing, SMOOTHING_INTERVAL};
+use solo::Solo;
+use state::BandState;
+use undo_history::UndoHistory;
+
+use vst::api::Supported;
 use vst::buffer::AudioBuffer;
+use vst::channels::ChannelInfo;
 use vst::editor::Editor;
-use vst::plugin::{Category, Info, Plugin, PluginParameters};
+use vst::plugin::{CanDo, Category, HostCallback, Info, Plugin, PluginParameters};
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use atomic_f64::AtomicF64;
 
-const FILTER_COUNT: usize = 4;
+/// Bands in a new instance
+const FILTER_COUNT: usize = 8;
+const MIN_FILTER_COUNT: usize = 8;
+const MAX_FILTER_COUNT: usize = 24;
 const FILTER_POLE_COUNT: usize = 16;
+/// Kernel lengths linear phase mode can use. Longer ones are more accurate in the lows.
+const FIR_LENGTHS: [usize; 4] = [4096, 8192, 16384, 32768];
+/// VST 2 plugin ID, also written into program and bank files
+const UNIQUE_ID: i32 = 237953123;
 
 fn get_coefficients<T: audio_filters::units::FP>(
     kind: BandKind,
@@ -77,6 +111,72 @@ fn get_coefficients<T: audio_filters::units::FP>(
     }
 }
 
+/// Runs `filter` over the block, fading between the dry and filtered signal
+/// when the mix changes from `from` to `to`. Only the `lanes` set to 1 are changed.
+fn filter_block(
+    block: &mut [f64x4],
+    from: f64,
+    to: f64,
+    lanes: [f64; 2],
+    mut filter: impl FnMut(f64x4) -> f64x4,
+) {
+    if from == 1.0 && to == 1.0 && lanes == [1.0, 1.0] {
+        for audio in block.iter_mut() {
+            *audio = filter(*audio);
+        }
+    } else {
+        let step = (to - from) / block.len() as f64;
+        for (i, audio) in block.iter_mut().enumerate() {
+            let mix = from + step * (i + 1) as f64;
+            let mix = f64x4::from([mix * lanes[0], mix * lanes[1], 0.0, 0.0]);
+            let dry = *audio;
+            *audio = dry + (filter(dry) - dry) * mix;
+        }
+    }
+}
+
+/// Left and right to mid and side, in lanes 0 and 1
+fn encode_mid_side(block: &mut [f64x4]) {
+    for audio in block.iter_mut() {
+        let [l, r, _, _]: [f64; 4] = (*audio).into();
+        *audio = f64x4::from([(l + r) * 0.5, (l - r) * 0.5, 0.0, 0.0]);
+    }
+}
+
+fn decode_mid_side(block: &mut [f64x4]) {
+    for audio in block.iter_mut() {
+        let [m, s, _, _]: [f64; 4] = (*audio).into();
+        *audio = f64x4::from([m + s, m - s, 0.0, 0.0]);
+    }
+}
+
+/// Whether a band runs on mid/side, and the lanes it changes.
+/// Stereo bands always run on left/right, so their filter state stays valid
+/// when other bands change channel.
+fn channel_routing(channel: BandChannel) -> (bool, [f64; 2]) {
+    match channel {
+        BandChannel::Stereo => (false, [1.0, 1.0]),
+        BandChannel::Left => (false, [1.0, 0.0]),
+        BandChannel::Right => (false, [0.0, 1.0]),
+        BandChannel::Mid => (true, [1.0, 0.0]),
+        BandChannel::Side => (true, [0.0, 1.0]),
+    }
+}
+
+/// Multiplies the block by a gain going from `from` to `to`
+fn apply_gain(block: &mut [f64x4], from: f64, to: f64) {
+    if from != to {
+        let step = (to - from) / block.len() as f64;
+        for (i, audio) in block.iter_mut().enumerate() {
+            *audio = *audio * (from + step * (i + 1) as f64);
+        }
+    } else if from != 1.0 {
+        for audio in block.iter_mut() {
+            *audio = *audio * from;
+        }
+    }
+}
+
 pub struct EditorFilterData {
     pub params: Arc<BandParameters>,
 }
@@ -85,6 +185,37 @@ struct EQPlugin {
     params: Arc<EQEffectParameters>,
     editor: Option<EQPluginEditor>,
     filter_bands: Vec<WideLinkwitzRileyBand<f64x4>>,
+    /// What the filters were last set to, read together with `update_snapshot`
+    band_snapshots: Vec<Snapshot<BandState>>,
+    /// Bands read in this sub-block, only used once all of them are known to be from one state
+    read_snapshots: Vec<Snapshot<BandState>>,
+    band_smoothing: Vec<BandSmoothing>,
+    /// Fades each band in and out as it is switched on and off, or moved to another channel
+    band_fades: Vec<Crossfade>,
+    /// Channel each band is running on, which only changes while the band is faded out
+    band_channels: Vec<BandChannel>,
+    /// Fades between the input and the EQ when the host bypasses the plugin
+    bypass_fade: Crossfade,
+    input_trim: GainSmoothing,
+    /// Output gain plus auto gain
+    output_gain: GainSmoothing,
+    /// Auto gain for the Curve mode in dB, measured by the kernel builder thread
+    curve_compensation: Arc<AtomicF64>,
+    live_loudness: LiveLoudness,
+    /// Auto gain in dB, for the editor
+    compensation: Arc<AtomicF64>,
+    band_dynamics: Vec<BandDynamics>,
+    /// Gain each dynamic band is adding right now in dB, for the editor
+    dynamic_gain: Arc<Vec<AtomicF64>>,
+    solo: Solo,
+    convolver: Convolver,
+    /// Stops the kernel thread when the plugin is dropped
+    _kernel_builder: KernelBuilder,
+    /// Whether the DSP runs in linear phase, which only changes while `latency_fade` is off
+    linear_phase: bool,
+    /// Fades the output out and back in around a jump in latency
+    latency_fade: Crossfade,
+    latency_reporter: Arc<LatencyReporter>,
     time: Arc<AtomicF64>,
     sample_rate: Arc<AtomicF64>,
     block_size: i64,
@@ -92,17 +223,69 @@ struct EQPlugin {
 
 impl Default for EQPlugin {
     fn default() -> Self {
-        let params = Arc::new(EQEffectParameters::default());
+        EQPlugin::with_params(
+            Arc::new(EQEffectParameters::default()),
+            HostCallback::default(),
+        )
+    }
+}
+
+impl EQPlugin {
+    /// Sets up the DSP for however many bands `params` has
+    fn with_params(params: Arc<EQEffectParameters>, host: HostCallback) -> Self {
         let time = Arc::new(AtomicF64::new(0.0));
         let sample_rate = Arc::new(AtomicF64::new(48000.0));
+        let compensation = Arc::new(AtomicF64::new(0.0));
 
         let coeffs = FilterBandCoefficients::bell(1000.0, 0.0, 1.0, 48000.0);
         let coeffs = WideFilterBandCoefficients::from(coeffs);
         let coeffs = WideLinkwitzRileyCoefficients::from(coeffs);
 
-        let filter_bands = (0..FILTER_COUNT)
+        let filter_bands = (0..params.bands.len())
             .map(|_| WideLinkwitzRileyBand::from(&coeffs))
             .collect::<Vec<WideLinkwitzRileyBand<f64x4>>>();
+        let band_snapshots = params
+            .bands
+            .iter()
+            .map(|band| Snapshot::new(band.get_state()))
+            .collect::<Vec<Snapshot<BandState>>>();
+        let band_smoothing = params
+            .bands
+            .iter()
+            .map(|band| BandSmoothing::new(&band.get_state()))
+            .collect::<Vec<BandSmoothing>>();
+        let band_fades = params
+            .bands
+            .iter()
+            .map(|band| Crossfade::new(band.is_enabled()))
+            .collect::<Vec<Crossfade>>();
+        let band_channels = params
+            .bands
+            .iter()
+            .map(|band| band.get_channel())
+            .collect::<Vec<BandChannel>>();
+        let band_dynamics = params
+            .bands
+            .iter()
+            .map(|_| BandDynamics::new(sample_rate.get()))
+            .collect::<Vec<BandDynamics>>();
+        let dynamic_gain = Arc::new(
+            params
+                .bands
+                .iter()
+                .map(|_| AtomicF64::new(0.0))
+                .collect::<Vec<AtomicF64>>(),
+        );
+        let curve_compensation = Arc::new(AtomicF64::new(0.0));
+        let (convolver, kernel_builder) = linear_phase::start(
+            params.clone(),
+            sample_rate.clone(),
+            curve_compensation.clone(),
+        );
+        let latency_reporter = Arc::new(LatencyReporter::new(&params, host));
+        if let Ok(mut reporter) = params.latency_reporter.lock() {
+            *reporter = Some(latency_reporter.clone());
+        }
 
         Self {
             params: params.clone(),
@@ -114,11 +297,150 @@ impl Default for EQPlugin {
                 state: Arc::new(EditorState {
                     params: params.clone(),
                     sample_rate: sample_rate.clone(),
+                    compensation: compensation.clone(),
+                    dynamic_gain: dynamic_gain.clone(),
+                    preset_browser: Mutex::new(PresetBrowser::default()),
+                    history: Mutex::new(UndoHistory::default()),
+                    curve_fit: Mutex::new(CurveFitPanel::default()),
+                    clipboard_message: Mutex::new(String::new()),
+                    latched_solo: Mutex::new(None),
+                    latency_reporter: latency_reporter.clone(),
                 }),
             }),
             filter_bands,
+            read_snapshots: band_snapshots.clone(),
+            band_snapshots,
+            band_smoothing,
+            band_fades,
+            band_channels,
+            bypass_fade: Crossfade::new(true),
+            input_trim: GainSmoothing::new(params.input_trim.get()),
+            output_gain: GainSmoothing::new(params.output_gain.get()),
+            curve_compensation,
+            live_loudness: LiveLoudness::new(48000),
+            compensation,
+            band_dynamics,
+            dynamic_gain,
+            solo: Solo::new(sample_rate.get()),
+            convolver,
+            _kernel_builder: kernel_builder,
+            linear_phase: false,
+            latency_fade: Crossfade::new(true),
+            latency_reporter,
         }
     }
+
+    /// Picks up parameter changes and moves gliding bands along by `samples`
+    fn update_bands(&mut self, sample_rate: f64, samples: usize) {
+        // Every band is read before any is used, and the reads are thrown away if a program
+        // or preset was applied meanwhile, so the DSP never runs half of one and half of another
+        let generation = self.params.state_generation();
+        for (band, snapshot) in self.params.bands.iter().zip(self.read_snapshots.iter_mut()) {
+            band.update_snapshot(snapshot);
+        }
+        let consistent = generation.is_some() && self.params.state_generation() == generation;
+
+        let smoothing_time = self.params.smoothing.get() / 1000.0;
+        for i in 0..self.params.bands.len() {
+            let smoothing = &mut self.band_smoothing[i];
+            let changed =
+                consistent && self.read_snapshots[i].value != self.band_snapshots[i].value;
+            if consistent {
+                self.band_snapshots[i] = self.read_snapshots[i];
+            } else {
+                // Read again next time
+                self.read_snapshots[i] = self.band_snapshots[i];
+            }
+            let snapshot = &self.band_snapshots[i].value;
+            // A band moved to another channel fades out where it is, then back in on the new one,
+            // so its filter never jumps between left/right and mid/side audio
+            let channel = BandChannel::from_u8(snapshot.channel as u8);
+            if channel != self.band_channels[i] && self.band_fades[i].is_off() {
+                self.band_channels[i] = channel;
+            }
+            self.band_fades[i].set(snapshot.enabled >= 0.5 && channel == self.band_channels[i]);
+            if changed {
+                smoothing.set_target(snapshot, smoothing_time);
+            }
+            // Dynamic gain glides like the band's own gain, so a fast attack doesn't zipper
+            let dynamics = &mut self.band_dynamics[i];
+            if changed || dynamics.gain_changed() {
+                smoothing.set_gain_offset(dynamics.apply_gain(), smoothing_time);
+            } else if smoothing.is_settled() {
+                continue;
+            }
+            // Switched off bands catch up when they are switched back on
+            if self.band_fades[i].is_off() {
+                continue;
+            }
+            smoothing.step(samples, sample_rate);
+
+            let state = smoothing.current();
+            dynamics.set_detector(state.freq, state.bw, sample_rate);
+            let mut gain = state.gain;
+            if state.mode == 1.0 {
+                gain *= 0.5;
+            }
+            let kind = BandKind::from_u8(state.kind as u8);
+            let coeffs =
+                get_coefficients(kind, state.freq, gain, state.bw, state.slope, sample_rate);
+            let coeffs = WideFilterBandCoefficients::from(coeffs);
+            let coeffs = WideLinkwitzRileyCoefficients::from(coeffs);
+            self.filter_bands[i].update(&coeffs);
+        }
+    }
+
+    /// Points the solo filter at the band the editor solos, following it as it glides.
+    /// Switched off bands don't glide, so their settings are used as they are.
+    fn update_solo(&mut self, sample_rate: f64) {
+        let band = match self.params.get_solo() {
+            Some(i) if i < self.band_smoothing.len() => Some(if self.band_fades[i].is_off() {
+                self.band_snapshots[i].value
+            } else {
+                self.band_smoothing[i].current()
+            }),
+            _ => None,
+        };
+        self.solo.update(band.as_ref(), sample_rate);
+    }
+
+    /// Switching linear phase or its kernel length makes the output jump in time,
+    /// so the output fades out, the change is made, and it fades back in
+    fn update_latency(&mut self) {
+        let linear_phase = self.params.is_linear_phase();
+        if self.latency_fade.is_off() && linear_phase != self.linear_phase {
+            // The convolver isn't fed while linear phase is off
+            if linear_phase {
+                self.convolver.reset();
+            }
+            self.linear_phase = linear_phase;
+        }
+        let settled = linear_phase == self.linear_phase
+            && (!linear_phase
+                || (self.convolver.is_ready() && !self.convolver.latency_change_pending()));
+        self.latency_fade.set(settled);
+        self.convolver.set_muted(self.latency_fade.is_off());
+    }
+
+    /// Glides the input trim and output gain along by `samples`,
+    /// returning the linear gains at the start and end of them
+    fn update_gains(&mut self, sample_rate: f64, samples: usize) -> ((f64, f64), (f64, f64)) {
+        let smoothing_time = self.params.smoothing.get() / 1000.0;
+        let compensation = match self.params.get_auto_gain() {
+            AutoGain::Off => 0.0,
+            AutoGain::Curve => self.curve_compensation.get(),
+            AutoGain::Live => self.live_loudness.compensation_db(),
+        };
+        self.compensation.set(compensation);
+        self.input_trim
+            .set_target(self.params.input_trim.get(), smoothing_time);
+        self.output_gain
+            .set_target(self.params.output_gain.get() + compensation, smoothing_time);
+        (
+            self.input_trim.step(samples, sample_rate),
+            self.output_gain.step(samples, sample_rate),
+        )
+    }
 }
 
 fn setup_logging() {
@@ -140,35 +462,75 @@ fn setup_logging() {
 }
 
 impl Plugin for EQPlugin {
+    fn new(host: HostCallback) -> Self {
+        EQPlugin::with_params(Arc::new(EQEffectParameters::default()), host)
+    }
+
     fn get_info(&self) -> Info {
         Info {
             name: "IMGUI EQ in Rust 0.1".to_string(),
             vendor: "DGriffin".to_string(),
-            unique_id: 237953123,
+            unique_id: UNIQUE_ID,
             version: 2,
-            inputs: 2,
+            // The second pair is the sidechain for dynamic bands
+            inputs: 4,
             outputs: 2,
             // This `parameters` bit is important; without it, none of our
             // parameters will be shown!
             parameters: self.params.len() as i32,
+            presets: self.params.program_count() as i32,
+            preset_chunks: true,
             category: Category::Effect,
+            initial_delay: linear_phase::plugin_latency(&self.params) as i32,
             ..Default::default()
         }
     }
 
+    fn get_input_info(&self, input: i32) -> ChannelInfo {
+        let name = match input {
+            0 => "Left",
+            1 => "Right",
+            2 => "Sidechain Left",
+            _ => "Sidechain Right",
+        };
+        ChannelInfo::new(name.to_string(), None, true, None)
+    }
+
     fn set_sample_rate(&mut self, rate: f32) {
         self.sample_rate.set(rate as f64);
+        self.live_loudness.set_sample_rate(rate as usize);
+        for dynamics in self.band_dynamics.iter_mut() {
+            dynamics.set_sample_rate(rate as f64);
+        }
     }
 
     fn set_block_size(&mut self, block_size: i64) {
         self.block_size = block_size;
     }
 
+    // Latency changes are reported here, from the editor and from the host's
+    // parameter and chunk calls, never from process
+    fn resume(&mut self) {
+        self.latency_reporter.report(&self.params);
+    }
+
     fn init(&mut self) {
         setup_logging();
         //setup_logger();
     }
 
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        match can_do {
+            CanDo::Bypass => Supported::Yes,
+            _ => Supported::Maybe,
+        }
+    }
+
+    // The host keeps calling process while bypassed, which fades the EQ out
+    fn set_bypass(&mut self, bypass: bool) {
+        self.params.bypass.set(bypass);
+    }
+
     fn get_editor(&mut self) -> Option<Box<dyn Editor>> {
         if let Some(editor) = self.editor.take() {
             Some(Box::new(editor) as Box<dyn Editor>)
@@ -181,60 +543,160 @@ impl Plugin for EQPlugin {
         //let b: i32 = a.iter().sum();
         //println!("{}", b);
         assert_no_alloc(|| {
-            println!("{}", vec![1.0][0]);
             self.time
                 .set(self.time.get() + (1.0 / self.sample_rate.get()) * self.block_size as f64);
             let sample_rate = self.sample_rate.get();
 
             let (inputs, outputs) = buffer.split();
+            // Some hosts only pass the main pair
+            let sidechain_input = if inputs.len() >= 4 {
+                Some((inputs.get(2), inputs.get(3)))
+            } else {
+                None
+            };
             let (inputs_left, inputs_right) = inputs.split_at(1);
             let (mut outputs_left, mut outputs_right) = outputs.split_at_mut(1);
 
-            let inputs_stereo = inputs_left[0].iter().zip(inputs_right[0].iter());
-            let outputs_stereo = outputs_left[0].iter_mut().zip(outputs_right[0].iter_mut());
+            let inputs_stereo = inputs_left[0]
+                .chunks(SMOOTHING_INTERVAL)
+                .zip(inputs_right[0].chunks(SMOOTHING_INTERVAL));
+            let outputs_stereo = outputs_left[0]
+                .chunks_mut(SMOOTHING_INTERVAL)
+                .zip(outputs_right[0].chunks_mut(SMOOTHING_INTERVAL));
+
+            let mut block = [f64x4::from([0.0; 4]); SMOOTHING_INTERVAL];
+            let mut dry = [f64x4::from([0.0; 4]); SMOOTHING_INTERVAL];
+            // The sidechain in left/right and in mid/side, for bands with an external key
+            let mut sidechain = [f64x4::from([0.0; 4]); SMOOTHING_INTERVAL];
+            let mut sidechain_mid_side = [f64x4::from([0.0; 4]); SMOOTHING_INTERVAL];
+            self.bypass_fade.set(!self.params.bypass.get());
+
+            // Coefficients only change between sub-blocks, the loops below only filter
+            let sub_blocks = inputs_stereo.zip(outputs_stereo).enumerate();
+            for (sub_block, ((input_l, input_r), (output_l, output_r))) in sub_blocks {
+                let samples = input_l.len();
+                let start = sub_block * SMOOTHING_INTERVAL;
+                self.update_bands(sample_rate, samples);
+                self.update_solo(sample_rate);
+                self.update_latency();
+                let linear_phase = self.linear_phase;
+
+                let ((trim_from, trim_to), (gain_from, gain_to)) =
+                    self.update_gains(sample_rate, samples);
+                let live = self.params.get_auto_gain() == AutoGain::Live;
+
+                let (eq_from, eq_to) = self.bypass_fade.step(samples, sample_rate);
+                let (latency_from, latency_to) = self.latency_fade.step(samples, sample_rate);
+                // Linear phase keeps its latency while bypassed, and a change in latency fades
+                let unmuted = latency_from == 1.0 && latency_to == 1.0;
+                if eq_from == 0.0 && eq_to == 0.0 && !linear_phase && unmuted {
+                    output_l.copy_from_slice(input_l);
+                    output_r.copy_from_slice(input_r);
+                    continue;
+                }
+
+                let block = &mut block[..samples];
+                for (audio, (l, r)) in block.iter_mut().zip(input_l.iter().zip(input_r.iter())) {
+                    *audio = f64x4::from([*l as f64, *r as f64, 0.0, 0.0]);
+                }
+                let dry = &mut dry[..samples];
+                dry.copy_from_slice(block);
+
+                let sidechain = &mut sidechain[..samples];
+                let sidechain_mid_side = &mut sidechain_mid_side[..samples];
+                if let Some((sidechain_l, sidechain_r)) = sidechain_input {
+                    let sidechain_l = &sidechain_l[start..start + samples];
+                    let sidechain_r = &sidechain_r[start..start + samples];
+                    for (audio, (l, r)) in sidechain
+                        .iter_mut()
+                        .zip(sidechain_l.iter().zip(sidechain_r.iter()))
+                    {
+                        *audio = f64x4::from([*l as f64, *r as f64, 0.0, 0.0]);
+                    }
+                    sidechain_mid_side.copy_from_slice(sidechain);
+                    encode_mid_side(sidechain_mid_side);
+                }
+
+                apply_gain(block, trim_from, trim_to);
+                if live {
+                    self.live_loudness.measure_input(block);
+                }
 
-            for (input_pair, output_pair) in inputs_stereo.zip(outputs_stereo) {
-                for (i, band) in self.params.bands.iter().enumerate() {
-                    if !band.dsp_update() {
-                        continue;
+                if linear_phase {
+                    self.convolver.process(block, dry);
+                    // Dynamic bands are static in linear phase
+                    for dynamic_gain in self.dynamic_gain.iter() {
+                        dynamic_gain.set(0.0);
                     }
-                    let f0 = band.freq.get() as f64;
-                    let mut gain = band.gain.get() as f64;
-                    let bw = band.bw.get() as f64;
-                    let slope = band.get_slope() as f64;
-                    //let mode = band.get_mode() as f64;
-                    let fs = sample_rate as f64;
-
-                    if self.params.bands[i].get_mode() == 1.0 {
-                        gain *= 0.5;
+                } else {
+                    let bands = self
+                        .filter_bands
+                        .iter_mut()
+                        .zip(self.band_snapshots.iter().zip(self.band_channels.iter()))
+                        .zip(self.band_fades.iter_mut())
+                        .zip(self.band_dynamics.iter_mut().zip(self.dynamic_gain.iter()));
+                    // Only switches between left/right and mid/side when the next band needs it
+                    let mut mid_side = false;
+                    for (((band, (snapshot, channel)), fade), (dynamics, dynamic_gain)) in bands {
+                        let (from, to) = fade.step(samples, sample_rate);
+                        if from == 0.0 && to == 0.0 {
+                            continue;
+                        }
+                        let (band_mid_side, lanes) = channel_routing(*channel);
+                        if band_mid_side != mid_side {
+                            if band_mid_side {
+                                encode_mid_side(block);
+                            } else {
+                                decode_mid_side(block);
+                            }
+                            mid_side = band_mid_side;
+                        }
+                        let key = if mid_side {
+                            &*sidechain_mid_side
+                        } else {
+                            &*sidechain
+                        };
+                        dynamics.process(block, key, lanes, &snapshot.value, sample_rate);
+                        dynamic_gain.set(dynamics.gain_db());
+                        if snapshot.value.mode == 0.0 {
+                            filter_block(block, from, to, lanes, |audio| {
+                                (band.filter1.process)(&mut band.filter1, audio).into()
+                            });
+                        } else {
+                            filter_block(block, from, to, lanes, |audio| {
+                                (band.process)(band, audio).into()
+                            });
+                        }
+                    }
+                    if mid_side {
+                        decode_mid_side(block);
                     }
-
-                    let coeffs = get_coefficients(band.get_kind(), f0, gain, bw, slope, fs);
-                    let coeffs = WideFilterBandCoefficients::from(coeffs);
-                    let coeffs = WideLinkwitzRileyCoefficients::from(coeffs);
-                    self.filter_bands[i].update(&coeffs);
                 }
 
-                let (input_l, input_r) = input_pair;
-                let (output_l, output_r) = output_pair;
-
-                let mut audio = f64x4::from([*input_l as f64, *input_r as f64, 0.0, 0.0]);
-
-                for i in 0..self.filter_bands.len() {
-                    if self.params.bands[i].get_mode() == 0.0 {
-                        audio = (self.filter_bands[i].filter1.process)(
-                            &mut self.filter_bands[i].filter1,
-                            audio,
-                        )
-                        .into();
-                    } else {
-                        audio =
-                            (self.filter_bands[i].process)(&mut self.filter_bands[i], audio).into();
+                if live {
+                    self.live_loudness.measure_output(block);
+                }
+                apply_gain(block, gain_from, gain_to);
+                // Solo replaces the EQ, and is bypassed along with it
+                self.solo.process(dry, block, sample_rate);
+
+                // The EQ itself is faded like a band, with the input as the dry signal
+                if eq_from != 1.0 || eq_to != 1.0 {
+                    let step = (eq_to - eq_from) / samples as f64;
+                    for (i, (audio, dry)) in block.iter_mut().zip(dry.iter()).enumerate() {
+                        *audio = *dry + (*audio - *dry) * (eq_from + step * (i + 1) as f64);
                     }
                 }
-                let audio: [f64; 4] = audio.into();
-                *output_l = audio[0] as f32;
-                *output_r = audio[1] as f32;
+                apply_gain(block, latency_from, latency_to);
+
+                for (audio, (l, r)) in block
+                    .iter()
+                    .zip(output_l.iter_mut().zip(output_r.iter_mut()))
+                {
+                    let audio: [f64; 4] = (*audio).into();
+                    *l = audio[0] as f32;
+                    *r = audio[1] as f32;
+                }
             }
         });
     }
@@ -249,8 +711,8 @@ impl Plugin for EQPlugin {
 impl PluginParameters for EQEffectParameters {
     // the `get_parameter` function reads the value of a parameter.
     fn get_parameter(&self, index: i32) -> f32 {
-        if (index as usize) < self.len() {
-            self[index as usize].get_normalized() as f32
+        if let Some(parameter) = self.get(index as usize) {
+            parameter.get_normalized() as f32
         } else {
             0.0
         }
@@ -259,8 +721,14 @@ impl PluginParameters for EQEffectParameters {
     // the `set_parameter` function sets the value of a parameter.
     fn set_parameter(&self, index: i32, val: f32) {
         #[allow(clippy::single_match)]
-        if (index as usize) < self.len() {
-            self[index as usize].set_normalized(val as f64);
+        if let Some(parameter) = self.get(index as usize) {
+            parameter.set_normalized(val as f64);
+        }
+        // Hosts that restore or edit these one by one change the latency here
+        match self.registry.get(index as usize) {
+            Some(ParameterId::Global(GlobalField::LinearPhase))
+            | Some(ParameterId::Global(GlobalField::FirLength)) => self.report_latency(true),
+            _ => (),
         }
     }
 
@@ -268,8 +736,26 @@ impl PluginParameters for EQEffectParameters {
     // format it into a string that makes the most since.
 
     fn get_parameter_text(&self, index: i32) -> String {
-        if (index as usize) < self.len() {
-            self[index as usize].get_display()
+        if let Some(parameter) = self.get(index as usize) {
+            parameter.get_display()
+        } else {
+            "".to_string()
+        }
+    }
+
+    // Values typed into the host, like "1.2k" or "-3dB"
+    fn string_to_parameter(&self, index: i32, text: String) -> bool {
+        if let Some(parameter) = self.get(index as usize) {
+            parameter.set_from_text(&text)
+        } else {
+            false
+        }
+    }
+
+    // Unit the host shows next to the text
+    fn get_parameter_label(&self, index: i32) -> String {
+        if let Some(parameter) = self.get(index as usize) {
+            parameter.get_label()
         } else {
             "".to_string()
         }
@@ -277,12 +763,164 @@ impl PluginParameters for EQEffectParameters {
 
     // This shows the control's name.
     fn get_parameter_name(&self, index: i32) -> String {
-        if (index as usize) < self.len() {
-            self[index as usize].get_name()
+        if let Some(parameter) = self.get(index as usize) {
+            parameter.get_name()
         } else {
             "".to_string()
         }
     }
+
+    fn get_preset_num(&self) -> i32 {
+        self.current_program() as i32
+    }
+
+    fn change_preset(&self, preset: i32) {
+        if preset >= 0 {
+            self.change_program(preset as usize);
+            self.report_latency(false);
+        }
+    }
+
+    fn get_preset_name(&self, preset: i32) -> String {
+        if preset >= 0 {
+            self.get_program_name(preset as usize)
+        } else {
+            "".to_string()
+        }
+    }
+
+    fn set_preset_name(&self, name: String) {
+        self.set_program_name(name);
+    }
+
+    // The full state goes through chunks so nothing is lost to normalization
+    fn get_preset_data(&self) -> Vec<u8> {
+        self.get_program_state().to_bytes()
+    }
+
+    fn get_bank_data(&self) -> Vec<u8> {
+        self.get_bank_state().to_bytes()
+    }
+
+    fn load_preset_data(&self, data: &[u8]) {
+        self.load_state_chunk(data);
+        self.report_latency(false);
+    }
+
+    fn load_bank_data(&self, data: &[u8]) {
+        self.load_bank_chunk(data);
+        self.report_latency(false);
+    }
 }
 
 plugin_main!(EQPlugin);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eq_effect_parameters::BandMode;
+
+    use std::time::Instant;
+
+    const BENCH_BANDS: usize = 16;
+    /// 96 dB/oct
+    const BENCH_SLOPE: f64 = 16.0;
+    const BENCH_SAMPLE_RATE: f64 = 48000.0;
+    const BENCH_BLOCK_SIZE: usize = 512;
+    const BENCH_BLOCKS: usize = 2000;
+
+    fn bench_plugin() -> EQPlugin {
+        let mut plugin = EQPlugin::with_params(
+            Arc::new(EQEffectParameters::new(BENCH_BANDS)),
+            HostCallback::default(),
+        );
+        plugin.set_sample_rate(BENCH_SAMPLE_RATE as f32);
+        let kinds = [
+            BandKind::LowShelf,
+            BandKind::Bell,
+            BandKind::HighShelf,
+            BandKind::Bell,
+        ];
+        for (i, band) in plugin.params.bands.iter().enumerate() {
+            band.set_state(&BandState {
+                kind: kinds[i % kinds.len()] as u8 as f64,
+                freq: 40.0 * 1.5f64.powi(i as i32),
+                gain: if i % 2 == 0 { 3.0 } else { -3.0 },
+                slope: BENCH_SLOPE,
+                mode: BandMode::LinkwitzRiley as u8 as f64,
+                ..BandState::default()
+            });
+        }
+        plugin
+    }
+
+    /// Moves the freq and gain of every band, like a host automating them once per block
+    fn automate(params: &EQEffectParameters, block: usize) {
+        let offset = (block % 2) as f64;
+        for (i, band) in params.bands.iter().enumerate() {
+            band.freq.set(40.0 * 1.5f64.powi(i as i32) + offset);
+            band.gain.set(if i % 2 == 0 { 3.0 } else { -3.0 } + offset);
+        }
+    }
+
+    fn noise(len: usize) -> Vec<f32> {
+        let mut seed = 1u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect()
+    }
+
+    /// Seconds spent per second of audio
+    fn real_time_factor(start: Instant) -> f64 {
+        start.elapsed().as_secs_f64() * BENCH_SAMPLE_RATE / (BENCH_BLOCKS * BENCH_BLOCK_SIZE) as f64
+    }
+
+    fn time_process(plugin: &mut EQPlugin, automated: bool) -> f64 {
+        let inputs = vec![noise(BENCH_BLOCK_SIZE), noise(BENCH_BLOCK_SIZE)];
+        let mut outputs = vec![vec![0.0f32; BENCH_BLOCK_SIZE]; 2];
+        let input_pointers: Vec<*const f32> = inputs.iter().map(|x| x.as_ptr()).collect();
+        let mut output_pointers: Vec<*mut f32> =
+            outputs.iter_mut().map(|x| x.as_mut_ptr()).collect();
+
+        let start = Instant::now();
+        for block in 0..BENCH_BLOCKS {
+            if automated {
+                automate(&plugin.params, block);
+            }
+            let mut buffer = unsafe {
+                AudioBuffer::from_raw(
+                    2,
+                    2,
+                    input_pointers.as_ptr(),
+                    output_pointers.as_mut_ptr(),
+                    BENCH_BLOCK_SIZE,
+                )
+            };
+            plugin.process(&mut buffer);
+        }
+        let factor = real_time_factor(start);
+        assert!(outputs.iter().flatten().all(|x| x.is_finite()));
+        factor
+    }
+
+    /// CPU use with 16 bands at 96 dB/oct, with the bands still and automated every block.
+    /// Run with `cargo test --release -- --ignored --nocapture process_cpu`
+    #[test]
+    #[ignore]
+    fn process_cpu() {
+        let still = time_process(&mut bench_plugin(), false);
+        let automated = time_process(&mut bench_plugin(), true);
+        println!(
+            "{} bands at {} dB/oct, {} sample blocks, % of one core at {} Hz",
+            BENCH_BANDS,
+            BENCH_SLOPE * 6.0,
+            BENCH_BLOCK_SIZE,
+            BENCH_SAMPLE_RATE
+        );
+        println!("still:     {:.2}%", still * 100.0);
+        println!("automated: {:.2}%", automated * 100.0);
+    }
+}
@@ -0,0 +1,644 @@
+//! Linear phase mode, which runs the whole curve as one FIR filter.
+//!
+//! The kernel is designed from the combined magnitude response of the bands, so it has the
+//! same curve with no phase shift, at the cost of `latency` samples. Dynamic bands use their
+//! static gain. Kernels are built on their own thread whenever the bands change, and the
+//! audio thread crossfades to them over one partition. A kernel of another length has another
+//! latency, so it waits until the plugin has faded its output out. The audio thread runs a
+//! uniformly partitioned FFT convolution.
+//!
+//! Mid/side bands make each output depend on both inputs, so the kernel is a matrix of four
+//! filters. Left/right bands are treated as if they came before mid/side bands.
+
+use audio_filters::{linkwitz_riley::LinkwitzRileyCoefficients, units::ZSample};
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use ringbuf::{Consumer, Producer};
+use wide::f64x4;
+
+use crate::atomic_bool::AtomicBool;
+use crate::atomic_f64::AtomicF64;
+use crate::auto_gain::CurveLoudness;
+use crate::eq_effect_parameters::{AutoGain, BandChannel, BandKind, EQEffectParameters};
+use crate::get_coefficients;
+use crate::state::BandState;
+use crate::FIR_LENGTHS;
+
+use vst::plugin::HostCallback;
+
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Samples per partition, which is also the latency the convolution adds to the kernel's
+const PARTITION: usize = 512;
+const MAX_PARTITIONS: usize = FIR_LENGTHS[FIR_LENGTHS.len() - 1] / PARTITION;
+/// How often the builder thread looks for changes
+const BUILD_INTERVAL: Duration = Duration::from_millis(20);
+/// Kernels waiting to be picked up or dropped
+const KERNEL_QUEUE: usize = 4;
+/// `audioMasterIOChanged`, which makes the host read the latency again
+const HOST_IO_CHANGED: i32 = 13;
+/// `audioMasterGetCurrentProcessLevel`, which says which of the host's threads is calling
+const HOST_GET_PROCESS_LEVEL: i32 = 23;
+/// `kVstProcessLevelUser`, the host's user interface thread
+const PROCESS_LEVEL_USER: isize = 1;
+
+/// Samples the output is behind the input with a kernel of `length`
+pub fn latency(length: usize) -> usize {
+    PARTITION + length / 2
+}
+
+/// The most `latency` can be
+fn max_latency() -> usize {
+    latency(FIR_LENGTHS[FIR_LENGTHS.len() - 1])
+}
+
+/// Latency the host should compensate for with the current parameters
+pub fn plugin_latency(params: &EQEffectParameters) -> usize {
+    if params.is_linear_phase() {
+        latency(params.get_fir_length())
+    } else {
+        0
+    }
+}
+
+/// Tells the host when the latency changes. Never used on the audio thread, since the host
+/// may allocate or restart processing when it is told. Changes are reported from `resume`,
+/// the editor's idle, program and chunk loads, and parameter changes the host makes from
+/// its user interface.
+pub struct LatencyReporter {
+    host: HostCallback,
+    /// Latency the host was last told about
+    reported: AtomicUsize,
+}
+
+impl LatencyReporter {
+    pub fn new(params: &EQEffectParameters, host: HostCallback) -> LatencyReporter {
+        // The host reads the first latency from `Info::initial_delay`
+        let reported = AtomicUsize::new(plugin_latency(params));
+        LatencyReporter { host, reported }
+    }
+
+    /// vst 0.2 only reads `Info::initial_delay` when the plugin is created,
+    /// so a new latency is written into the `AEffect` before telling the host
+    pub fn report(&self, params: &EQEffectParameters) {
+        let latency = plugin_latency(params);
+        if self.reported.swap(latency, Ordering::Relaxed) == latency {
+            return;
+        }
+        let effect = self.host.raw_effect();
+        if effect.is_null() {
+            return;
+        }
+        unsafe {
+            (*effect).initialDelay = latency as i32;
+        }
+        if let Some(callback) = self.host.raw_callback() {
+            callback(effect, HOST_IO_CHANGED, 0, 0, std::ptr::null_mut(), 0.0);
+        }
+    }
+
+    /// For calls the host can also make from the audio thread, like `set_parameter`.
+    /// Only reports when the host says it is calling from its user interface, otherwise
+    /// the change is reported later from `resume` or the editor.
+    pub fn report_from_host_ui(&self, params: &EQEffectParameters) {
+        let effect = self.host.raw_effect();
+        if effect.is_null() {
+            return;
+        }
+        if let Some(callback) = self.host.raw_callback() {
+            let level = callback(
+                effect,
+                HOST_GET_PROCESS_LEVEL,
+                0,
+                0,
+                std::ptr::null_mut(),
+                0.0,
+            );
+            if level == PROCESS_LEVEL_USER {
+                self.report(params);
+            }
+        }
+    }
+}
+
+/// Index of the filter from `input` to `output` in `Kernel::filters`
+fn filter_index(input: usize, output: usize) -> usize {
+    output * 2 + input
+}
+
+/// Magnitude of the bands at `freqs`, for each of left, right, mid and side.
+/// Same math as the DSP, stereo bands change both left and right.
+fn lane_responses(bands: &[BandState], freqs: &[f64], sample_rate: f64) -> [Vec<f64>; 4] {
+    let mut lanes = [
+        vec![1.0; freqs.len()],
+        vec![1.0; freqs.len()],
+        vec![1.0; freqs.len()],
+        vec![1.0; freqs.len()],
+    ];
+    for band in bands.iter().filter(|band| band.enabled >= 0.5) {
+        let mut gain = band.gain;
+        if band.mode == 1.0 {
+            gain *= 0.5;
+        }
+        let kind = BandKind::from_u8(band.kind as u8);
+        let coeffs = get_coefficients(kind, band.freq, gain, band.bw, band.slope, sample_rate);
+        let coeffs = LinkwitzRileyCoefficients::from(coeffs);
+        let changed: &[usize] = match BandChannel::from_u8(band.channel as u8) {
+            BandChannel::Stereo => &[0, 1],
+            BandChannel::Left => &[0],
+            BandChannel::Right => &[1],
+            BandChannel::Mid => &[2],
+            BandChannel::Side => &[3],
+        };
+        for (i, freq) in freqs.iter().enumerate() {
+            let z = ZSample::<f64>::new(freq.min(sample_rate * 0.49), sample_rate);
+            let magnitude = if band.mode == 0.0 {
+                coeffs.coeffs.get_bode_sample(z).norm()
+            } else {
+                coeffs.get_bode_sample(z).norm()
+            };
+            for lane in changed.iter() {
+                lanes[*lane][i] *= magnitude;
+            }
+        }
+    }
+    lanes
+}
+
+/// Partitioned spectra of the filters from each input to each output
+pub struct Kernel {
+    length: usize,
+    /// Indexed by `filter_index`, each has `length / PARTITION` partitions
+    filters: [Vec<Vec<Complex<f64>>>; 4],
+    /// Whether each output depends on both inputs
+    cross: bool,
+}
+
+impl Kernel {
+    /// Passes the input through delayed, for before the first kernel is built
+    fn flat(length: usize, planner: &mut RealFftPlanner<f64>) -> Kernel {
+        let response = vec![1.0; length / 2 + 1];
+        let zero = vec![0.0; length / 2 + 1];
+        Kernel::from_responses(
+            [&response[..], &zero, &zero, &response],
+            false,
+            length,
+            planner,
+        )
+    }
+
+    pub fn design(
+        bands: &[BandState],
+        length: usize,
+        sample_rate: f64,
+        planner: &mut RealFftPlanner<f64>,
+    ) -> Kernel {
+        let freqs = (0..=length / 2)
+            .map(|i| i as f64 * sample_rate / length as f64)
+            .collect::<Vec<f64>>();
+        let [left, right, mid, side] = lane_responses(bands, &freqs, sample_rate);
+        let cross = mid != side;
+        // Left/right first, then through mid/side, as a matrix from inputs to outputs
+        let matrix = |lane: &[f64], sign: f64| {
+            lane.iter()
+                .zip(mid.iter().zip(side.iter()))
+                .map(|(x, (m, s))| 0.5 * (m + s * sign) * x)
+                .collect::<Vec<f64>>()
+        };
+        let left_to_left = matrix(&left, 1.0);
+        let right_to_left = matrix(&right, -1.0);
+        let left_to_right = matrix(&left, -1.0);
+        let right_to_right = matrix(&right, 1.0);
+        Kernel::from_responses(
+            [
+                &left_to_left[..],
+                &right_to_left,
+                &left_to_right,
+                &right_to_right,
+            ],
+            cross,
+            length,
+            planner,
+        )
+    }
+
+    /// Turns zero phase responses, `length / 2 + 1` bins from 0 Hz to Nyquist, into
+    /// windowed filters delayed by half their length, and splits them into partitions
+    fn from_responses(
+        responses: [&[f64]; 4],
+        cross: bool,
+        length: usize,
+        planner: &mut RealFftPlanner<f64>,
+    ) -> Kernel {
+        let inverse = planner.plan_fft_inverse(length);
+        let forward = planner.plan_fft_forward(PARTITION * 2);
+        let mut spectrum = inverse.make_input_vec();
+        let mut impulse = inverse.make_output_vec();
+        let mut partition = forward.make_input_vec();
+        let mut filters = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+        for (filter, response) in filters.iter_mut().zip(responses.iter()) {
+            // A delay of length / 2 flips the sign of every other bin
+            for (i, (bin, magnitude)) in spectrum.iter_mut().zip(response.iter()).enumerate() {
+                let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+                *bin = Complex::new(magnitude * sign, 0.0);
+            }
+            inverse
+                .process(&mut spectrum, &mut impulse)
+                .expect("buffers are made by the FFT");
+            // The inverse isn't normalized, and the convolution's inverse is folded in here too
+            let scale = 1.0 / (length * PARTITION * 2) as f64;
+            for (i, x) in impulse.iter_mut().enumerate() {
+                let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / length as f64).cos();
+                *x *= window * scale;
+            }
+            for samples in impulse.chunks(PARTITION) {
+                partition[..PARTITION].copy_from_slice(samples);
+                partition[PARTITION..].iter_mut().for_each(|x| *x = 0.0);
+                let mut bins = forward.make_output_vec();
+                forward
+                    .process(&mut partition, &mut bins)
+                    .expect("buffers are made by the FFT");
+                filter.push(bins);
+            }
+        }
+        Kernel {
+            length,
+            filters,
+            cross,
+        }
+    }
+}
+
+/// FFTs of two partitions, and the buffers they work in
+struct Transforms {
+    forward: Arc<dyn RealToComplex<f64>>,
+    inverse: Arc<dyn ComplexToReal<f64>>,
+    scratch: Vec<Complex<f64>>,
+    spectrum: Vec<Complex<f64>>,
+    buffer: Vec<f64>,
+}
+
+impl Transforms {
+    /// Sums the filters from both inputs to `output` into `buffer[PARTITION..]`.
+    /// `history[input][newest]` is the spectrum of the latest partition.
+    fn convolve(
+        &mut self,
+        kernel: &Kernel,
+        history: &[Vec<Vec<Complex<f64>>>; 2],
+        newest: usize,
+        output: usize,
+    ) {
+        self.spectrum
+            .iter_mut()
+            .for_each(|x| *x = Complex::new(0.0, 0.0));
+        for (input, history) in history.iter().enumerate() {
+            if !kernel.cross && input != output {
+                continue;
+            }
+            let filter = &kernel.filters[filter_index(input, output)];
+            for (age, partition) in filter.iter().enumerate() {
+                let spectrum = &history[(newest + MAX_PARTITIONS - age) % MAX_PARTITIONS];
+                for (sum, (x, h)) in self
+                    .spectrum
+                    .iter_mut()
+                    .zip(spectrum.iter().zip(partition.iter()))
+                {
+                    *sum += x * h;
+                }
+            }
+        }
+        // Real signals have no imaginary part at 0 Hz and Nyquist, rounding can leave some
+        let last = self.spectrum.len() - 1;
+        self.spectrum[0].im = 0.0;
+        self.spectrum[last].im = 0.0;
+        let _ = self.inverse.process_with_scratch(
+            &mut self.spectrum,
+            &mut self.buffer,
+            &mut self.scratch,
+        );
+    }
+}
+
+/// Runs on the audio thread, picking up kernels from the builder
+pub struct Convolver {
+    transforms: Transforms,
+    /// The last two partitions of each input
+    input: [Vec<f64>; 2],
+    /// Spectra of each input over the last `MAX_PARTITIONS` partitions
+    history: [Vec<Vec<Complex<f64>>>; 2],
+    /// Index of the newest spectrum in `history`
+    newest: usize,
+    /// The output of the last partition, played while the next one is collected
+    output: [Vec<f64>; 2],
+    /// The output of the previous kernel, while fading to a new one
+    fade_output: Vec<f64>,
+    /// Position in the partition
+    position: usize,
+    kernel: Box<Kernel>,
+    /// A kernel of another length, held until the output is muted
+    waiting: Option<Box<Kernel>>,
+    /// Whether the plugin has faded its output out, so the latency can jump
+    muted: bool,
+    /// Samples of input since the last reset
+    primed: usize,
+    /// The input, delayed to line up with the output for bypass and solo
+    dry_delay: DelayLine,
+    new_kernels: Consumer<Box<Kernel>>,
+    /// Kernels that were replaced go back to the builder, so they are freed off the audio thread
+    old_kernels: Producer<Box<Kernel>>,
+}
+
+impl Convolver {
+    fn new(
+        length: usize,
+        new_kernels: Consumer<Box<Kernel>>,
+        old_kernels: Producer<Box<Kernel>>,
+    ) -> Convolver {
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(PARTITION * 2);
+        let inverse = planner.plan_fft_inverse(PARTITION * 2);
+        let bins = forward.make_output_vec();
+        let scratch_len = forward.get_scratch_len().max(inverse.get_scratch_len());
+        Convolver {
+            input: [vec![0.0; PARTITION * 2], vec![0.0; PARTITION * 2]],
+            history: [
+                vec![bins.clone(); MAX_PARTITIONS],
+                vec![bins.clone(); MAX_PARTITIONS],
+            ],
+            newest: 0,
+            output: [vec![0.0; PARTITION], vec![0.0; PARTITION]],
+            fade_output: vec![0.0; PARTITION],
+            position: 0,
+            kernel: Box::new(Kernel::flat(length, &mut planner)),
+            waiting: None,
+            muted: false,
+            primed: 0,
+            dry_delay: DelayLine::new(max_latency()),
+            new_kernels,
+            old_kernels,
+            transforms: Transforms {
+                forward,
+                inverse,
+                scratch: vec![Complex::new(0.0, 0.0); scratch_len],
+                spectrum: bins,
+                buffer: vec![0.0; PARTITION * 2],
+            },
+        }
+    }
+
+    /// Latency of the output being played right now
+    pub fn latency(&self) -> usize {
+        latency(self.kernel.length)
+    }
+
+    /// Whether a kernel with another latency is waiting for the output to be muted
+    pub fn latency_change_pending(&self) -> bool {
+        self.waiting.is_some()
+    }
+
+    /// Whether enough input has come in since the last reset for the output to be whole
+    pub fn is_ready(&self) -> bool {
+        self.primed >= self.latency()
+    }
+
+    /// Set while the plugin's output is faded out, so a kernel with another latency can be
+    /// swapped in at the next partition without being heard
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Forgets the audio from before linear phase was switched on
+    pub fn reset(&mut self) {
+        for input in self.input.iter_mut() {
+            input.iter_mut().for_each(|x| *x = 0.0);
+        }
+        for history in self.history.iter_mut() {
+            for spectrum in history.iter_mut() {
+                spectrum
+                    .iter_mut()
+                    .for_each(|x| *x = Complex::new(0.0, 0.0));
+            }
+        }
+        for output in self.output.iter_mut() {
+            output.iter_mut().for_each(|x| *x = 0.0);
+        }
+        self.dry_delay.reset();
+        self.position = 0;
+        self.primed = 0;
+    }
+
+    /// Swaps in the next kernel, returning the one it replaced. A kernel of the same length
+    /// is swapped straight away, one of another length only while the output is muted.
+    fn next_kernel(&mut self) -> Option<Box<Kernel>> {
+        // Only picks up a kernel when the old one has somewhere to go
+        if self.old_kernels.is_full() {
+            return None;
+        }
+        if self.waiting.is_none() {
+            self.waiting = self.new_kernels.pop();
+        }
+        let swap = match &self.waiting {
+            Some(kernel) => kernel.length == self.kernel.length || self.muted,
+            None => false,
+        };
+        if swap {
+            let kernel = &mut self.kernel;
+            self.waiting
+                .take()
+                .map(|new_kernel| std::mem::replace(kernel, new_kernel))
+        } else {
+            None
+        }
+    }
+
+    /// Runs once a whole partition of input has been collected
+    fn process_partition(&mut self) {
+        let previous = self.next_kernel();
+        // Only a kernel with the same latency can be crossfaded to without a comb
+        let length = self.kernel.length;
+        let fade_from = previous
+            .as_ref()
+            .filter(|previous| previous.length == length);
+
+        self.newest = (self.newest + 1) % MAX_PARTITIONS;
+        let transforms = &mut self.transforms;
+        for (input, history) in self.input.iter_mut().zip(self.history.iter_mut()) {
+            transforms.buffer.copy_from_slice(input);
+            let _ = transforms.forward.process_with_scratch(
+                &mut transforms.buffer,
+                &mut history[self.newest],
+                &mut transforms.scratch,
+            );
+            input.copy_within(PARTITION.., 0);
+        }
+        for output in 0..2 {
+            if let Some(previous) = fade_from {
+                transforms.convolve(previous, &self.history, self.newest, output);
+                self.fade_output
+                    .copy_from_slice(&transforms.buffer[PARTITION..]);
+            }
+            transforms.convolve(&self.kernel, &self.history, self.newest, output);
+            self.output[output].copy_from_slice(&transforms.buffer[PARTITION..]);
+            if fade_from.is_some() {
+                for (i, (x, old)) in self.output[output]
+                    .iter_mut()
+                    .zip(self.fade_output.iter())
+                    .enumerate()
+                {
+                    let mix = (i + 1) as f64 / PARTITION as f64;
+                    *x = old + (*x - old) * mix;
+                }
+            }
+        }
+        if let Some(previous) = previous {
+            let _ = self.old_kernels.push(previous);
+        }
+    }
+
+    /// Replaces `block` with the output and delays `dry` to line up with it. The latency
+    /// only changes between partitions, so both follow it on the same sample.
+    pub fn process(&mut self, block: &mut [f64x4], dry: &mut [f64x4]) {
+        for (audio, dry) in block.iter_mut().zip(dry.iter_mut()) {
+            let [l, r, _, _]: [f64; 4] = (*audio).into();
+            self.input[0][PARTITION + self.position] = l;
+            self.input[1][PARTITION + self.position] = r;
+            *audio = f64x4::from([
+                self.output[0][self.position],
+                self.output[1][self.position],
+                0.0,
+                0.0,
+            ]);
+            *dry = self.dry_delay.process(*dry, self.latency());
+            self.primed = self.primed.saturating_add(1);
+            self.position += 1;
+            if self.position == PARTITION {
+                self.position = 0;
+                self.process_partition();
+            }
+        }
+    }
+}
+
+/// Delays the dry signal by the convolution's latency, so bypass and solo line up with it
+struct DelayLine {
+    buffer: Vec<f64x4>,
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(max_delay: usize) -> DelayLine {
+        DelayLine {
+            buffer: vec![f64x4::from([0.0; 4]); max_delay + 1],
+            position: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buffer
+            .iter_mut()
+            .for_each(|x| *x = f64x4::from([0.0; 4]));
+    }
+
+    fn process(&mut self, audio: f64x4, delay: usize) -> f64x4 {
+        let len = self.buffer.len();
+        let delay = delay.min(len - 1);
+        self.buffer[self.position] = audio;
+        let delayed = self.buffer[(self.position + len - delay) % len];
+        self.position = (self.position + 1) % len;
+        delayed
+    }
+}
+
+/// Builds kernels, and measures the curve for auto gain, on its own thread until it is dropped
+pub struct KernelBuilder {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for KernelBuilder {
+    fn drop(&mut self) {
+        self.running.set(false);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Starts the builder, which sends a kernel to the returned convolver whenever the bands,
+/// the kernel length or the sample rate change while linear phase is on. While auto gain is
+/// set to Curve it also keeps `curve_compensation` up to date, so the audio thread never
+/// measures the curve itself.
+pub fn start(
+    params: Arc<EQEffectParameters>,
+    sample_rate: Arc<AtomicF64>,
+    curve_compensation: Arc<AtomicF64>,
+) -> (Convolver, KernelBuilder) {
+    let (new_kernels, new_kernels_consumer) =
+        ringbuf::RingBuffer::<Box<Kernel>>::new(KERNEL_QUEUE).split();
+    let (old_kernels, old_kernels_consumer) =
+        ringbuf::RingBuffer::<Box<Kernel>>::new(KERNEL_QUEUE).split();
+    let convolver = Convolver::new(params.get_fir_length(), new_kernels_consumer, old_kernels);
+    let running = Arc::new(AtomicBool::new(true));
+    let thread = {
+        let running = running.clone();
+        thread::spawn(move || {
+            build_kernels(
+                &params,
+                &sample_rate,
+                &curve_compensation,
+                &running,
+                new_kernels,
+                old_kernels_consumer,
+            )
+        })
+    };
+    (
+        convolver,
+        KernelBuilder {
+            running,
+            thread: Some(thread),
+        },
+    )
+}
+
+fn build_kernels(
+    params: &EQEffectParameters,
+    sample_rate: &AtomicF64,
+    curve_compensation: &AtomicF64,
+    running: &AtomicBool,
+    mut new_kernels: Producer<Box<Kernel>>,
+    mut old_kernels: Consumer<Box<Kernel>>,
+) {
+    let mut planner = RealFftPlanner::new();
+    // What the last kernel was built from
+    let mut built: Option<(Vec<BandState>, usize, f64)> = None;
+    let mut curve_loudness = CurveLoudness::new(params.bands.len());
+    while running.get() {
+        while old_kernels.pop().is_some() {}
+        let build = params.is_linear_phase() && !new_kernels.is_full();
+        let measure = params.get_auto_gain() == AutoGain::Curve;
+        let generation = params.state_generation();
+        if (build || measure) && generation.is_some() {
+            let bands = params.get_state().bands;
+            let sample_rate = sample_rate.get();
+            // A state applied while reading could have left half of the bands behind
+            let consistent = params.state_generation() == generation;
+            if consistent && measure {
+                curve_compensation.set(curve_loudness.compensation_db(&bands, sample_rate));
+            }
+            let design = (bands, params.get_fir_length(), sample_rate);
+            if consistent && build && built.as_ref() != Some(&design) {
+                let (bands, length, sample_rate) = &design;
+                let kernel = Kernel::design(bands, *length, *sample_rate, &mut planner);
+                if new_kernels.push(Box::new(kernel)).is_ok() {
+                    built = Some(design);
+                }
+            }
+        }
+        thread::sleep(BUILD_INTERVAL);
+    }
+}
@@ -0,0 +1,475 @@
+//! Upgrades state chunks written by older versions of the plugin.
+//!
+//! Each step takes one layout to the next, so loading an old chunk runs every
+//! step in order until it reaches the current layout. The older layouts are
+//! frozen here and should never change once released.
+//!
+//! Version 1 is the first chunk format. The version before it had no chunk, so hosts
+//! kept its normalized parameters instead, and wrote them into `.fxp` and `.fxb` files.
+//! Those are read here with that version's mappings and index layout.
+
+use serde::{Deserialize, Serialize};
+
+use crate::eq_effect_parameters::{BandKind, BandMode};
+use crate::state::{BandState, BankState, EQState, StateError, STATE_VERSION};
+use crate::UNIQUE_ID;
+
+use std::convert::TryInto;
+
+/// Parameters of the version before chunks: kind, freq, gain, bw, slope and mode for
+/// 4 bands, without the last band's mode
+pub const LEGACY_PARAMETER_COUNT: usize = 23;
+const LEGACY_BANDS: usize = 4;
+const LEGACY_BAND_FIELDS: usize = 6;
+/// Bytes before the parameters in an `.fxp` program, and before the programs in an `.fxb` bank
+const FXP_HEADER: usize = 56;
+const FXB_HEADER: usize = 156;
+
+/// Version 1: plain values, exactly as read from each parameter.
+/// `kind` is in the 0..10 parameter range, `mode` in 0..2 and `slope` is not floored.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct BandStateV1 {
+    pub kind: f64,
+    pub freq: f64,
+    pub gain: f64,
+    pub bw: f64,
+    pub slope: f64,
+    pub mode: f64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct StateV1 {
+    pub version: u32,
+    pub bands: Vec<BandStateV1>,
+}
+
+/// Stores the kind, mode and slope the DSP actually used, instead of the raw parameter value.
+/// A kind of 8 or above fell back to LowPass. A mode of 2 ran the Linkwitz-Riley pair
+/// without halving the gain, which is Linkwitz-Riley at twice the gain.
+/// Only gains within the gain parameter's range survive the doubling.
+pub fn v1_to_v2(state: &StateV1) -> EQState {
+    EQState {
+        version: 2,
+        name: String::new(),
+        bands: state
+            .bands
+            .iter()
+            .map(|band| {
+                let (mode, gain) = match band.mode.floor() as u8 {
+                    0 => (BandMode::Butterworth, band.gain),
+                    1 => (BandMode::LinkwitzRiley, band.gain),
+                    _ => (BandMode::LinkwitzRiley, band.gain * 2.0),
+                };
+                BandState {
+                    kind: BandKind::from_u8(band.kind as u8) as u8 as f64,
+                    freq: band.freq,
+                    gain,
+                    bw: band.bw,
+                    slope: band.slope.floor(),
+                    mode: mode as u8 as f64,
+                    ..BandState::default()
+                }
+            })
+            .collect(),
+        globals: None,
+    }
+}
+
+/// Plain values from the normalized parameters of the version before chunks, through its
+/// mappings: kind 0..10, freq 20..20k on a squared curve, gain ±24, bw 0.1..24,
+/// slope 1..16 and mode 0..2. Those are the values version 1 stored, so `v1_to_v2` does the rest.
+/// A missing parameter is 0, which is what the last band's mode always was.
+pub fn legacy_parameters_to_v1(values: &[f32]) -> StateV1 {
+    let bands = (0..LEGACY_BANDS)
+        .map(|band| {
+            let field = |field: usize, min: f64, max: f64, curve: fn(f64) -> f64| {
+                let x = values
+                    .get(band * LEGACY_BAND_FIELDS + field)
+                    .map_or(0.0, |x| (*x as f64).max(0.0).min(1.0));
+                curve(x) * (max - min) + min
+            };
+            let linear = |x| x;
+            BandStateV1 {
+                kind: field(0, 0.0, 10.0, linear),
+                freq: field(1, 20.0, 20000.0, |x| x.powf(2.0)),
+                gain: field(2, -24.0, 24.0, linear),
+                bw: field(3, 0.1, 24.0, linear),
+                slope: field(4, 1.0, 16.0, linear),
+                mode: field(5, 0.0, 2.0, linear),
+            }
+        })
+        .collect();
+    StateV1 { version: 1, bands }
+}
+
+fn read_i32(data: &[u8], at: usize) -> Option<i32> {
+    Some(i32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// A program from an `.fxp` file written for this plugin without a chunk, which is a big
+/// endian header followed by each parameter as a big endian float. Returns the program name
+/// and the parameters, or `None` for anything else.
+fn read_fxp(data: &[u8]) -> Option<(String, Vec<f32>)> {
+    if data.get(0..4)? != b"CcnK" || data.get(8..12)? != b"FxCk" {
+        return None;
+    }
+    if read_i32(data, 16)? != UNIQUE_ID {
+        return None;
+    }
+    let count = read_i32(data, 24)?;
+    if count < 0 || count as usize > LEGACY_PARAMETER_COUNT + 1 {
+        return None;
+    }
+    let name = data.get(28..FXP_HEADER)?;
+    let name = name.split(|c| *c == 0).next().unwrap_or(&[]);
+    let values = (0..count as usize)
+        .map(|i| read_i32(data, FXP_HEADER + i * 4).map(|x| f32::from_bits(x as u32)))
+        .collect::<Option<Vec<f32>>>()?;
+    Some((String::from_utf8_lossy(name).into_owned(), values))
+}
+
+/// The programs of an `.fxb` bank of `.fxp` programs
+fn read_fxb(data: &[u8]) -> Option<Vec<(String, Vec<f32>)>> {
+    if data.get(0..4)? != b"CcnK" || data.get(8..12)? != b"FxBk" {
+        return None;
+    }
+    if read_i32(data, 16)? != UNIQUE_ID {
+        return None;
+    }
+    let mut programs = Vec::new();
+    let mut at = FXB_HEADER;
+    for _ in 0..read_i32(data, 24)?.max(0) {
+        let program = read_fxp(data.get(at..)?)?;
+        at += FXP_HEADER + program.1.len() * 4;
+        programs.push(program);
+    }
+    Some(programs)
+}
+
+fn legacy_program((name, values): (String, Vec<f32>)) -> EQState {
+    EQState {
+        name,
+        ..v1_to_v2(&legacy_parameters_to_v1(&values))
+    }
+}
+
+#[derive(Deserialize)]
+struct VersionOnly {
+    version: u32,
+}
+
+/// Reads a chunk of any known version and brings it up to `STATE_VERSION`
+pub fn migrate(data: &[u8]) -> Result<EQState, StateError> {
+    if data.is_empty() {
+        return Err(StateError::Empty);
+    }
+    if let Some(program) = read_fxp(data) {
+        ::log::info!("Migrating state from the parameters of a program file");
+        return Ok(legacy_program(program));
+    }
+    let version = serde_json::from_slice::<VersionOnly>(data)
+        .map_err(StateError::Malformed)?
+        .version;
+    match version {
+        1 => {
+            ::log::info!("Migrating state from version 1");
+            let state: StateV1 = serde_json::from_slice(data).map_err(StateError::Malformed)?;
+            Ok(v1_to_v2(&state))
+        }
+        STATE_VERSION => serde_json::from_slice(data).map_err(StateError::Malformed),
+        _ => Err(StateError::UnsupportedVersion(version)),
+    }
+}
+
+#[derive(Deserialize)]
+struct BankProbe {
+    programs: Option<serde::de::IgnoredAny>,
+}
+
+/// Banks before version 2 were the same chunk as a single program,
+/// those are loaded into the current program.
+pub fn migrate_bank(data: &[u8]) -> Result<BankState, StateError> {
+    if let Some(programs) = read_fxb(data) {
+        ::log::info!("Migrating a bank from the parameters of a bank file");
+        return Ok(BankState {
+            version: STATE_VERSION,
+            current_program: 0,
+            programs: programs.into_iter().map(legacy_program).collect(),
+            ab: Default::default(),
+        });
+    }
+    let is_bank = serde_json::from_slice::<BankProbe>(data)
+        .map(|probe| probe.programs.is_some())
+        .unwrap_or(false);
+    if !is_bank {
+        let state = migrate(data)?;
+        return Ok(BankState {
+            version: STATE_VERSION,
+            current_program: 0,
+            programs: vec![state],
+            ab: Default::default(),
+        });
+    }
+
+    let version = serde_json::from_slice::<VersionOnly>(data)
+        .map_err(StateError::Malformed)?
+        .version;
+    match version {
+        STATE_VERSION => serde_json::from_slice(data).map_err(StateError::Malformed),
+        _ => Err(StateError::UnsupportedVersion(version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn band_v1(kind: f64, gain: f64, slope: f64, mode: f64) -> BandStateV1 {
+        BandStateV1 {
+            kind,
+            freq: 440.0,
+            gain,
+            bw: 1.5,
+            slope,
+            mode,
+        }
+    }
+
+    /// Filter stages a band ran through in version 1, and the gain of each one
+    fn v1_stages(band: &BandStateV1) -> (usize, f64) {
+        match band.mode.floor() as u8 {
+            0 => (1, band.gain),
+            1 => (2, band.gain * 0.5),
+            _ => (2, band.gain),
+        }
+    }
+
+    /// The same for the current DSP
+    fn v2_stages(band: &BandState) -> (usize, f64) {
+        match BandMode::from_u8(band.mode as u8) {
+            BandMode::Butterworth => (1, band.gain),
+            BandMode::LinkwitzRiley => (2, band.gain * 0.5),
+        }
+    }
+
+    #[test]
+    fn v1_to_v2_keeps_what_the_dsp_ran() {
+        let bands = vec![
+            band_v1(0.0, 3.0, 1.0, 0.0),
+            band_v1(3.7, -4.0, 2.6, 0.4),
+            band_v1(9.5, 0.0, 16.0, 1.5),
+            band_v1(4.0, 6.0, 4.0, 2.0),
+            band_v1(5.2, -10.0, 1.0, 1.0),
+        ];
+        let migrated = v1_to_v2(&StateV1 {
+            version: 1,
+            bands: bands.clone(),
+        });
+        assert_eq!(migrated.version, 2);
+        assert_eq!(migrated.globals, None);
+        assert_eq!(migrated.bands.len(), bands.len());
+
+        let kinds: Vec<f64> = migrated.bands.iter().map(|band| band.kind).collect();
+        let slopes: Vec<f64> = migrated.bands.iter().map(|band| band.slope).collect();
+        assert_eq!(kinds, vec![0.0, 3.0, 1.0, 4.0, 5.0]);
+        assert_eq!(slopes, vec![1.0, 2.0, 16.0, 4.0, 1.0]);
+        for (old, new) in bands.iter().zip(migrated.bands.iter()) {
+            assert_eq!(new.freq, old.freq);
+            assert_eq!(new.bw, old.bw);
+            assert_eq!(v2_stages(new), v1_stages(old));
+            assert_eq!(new.enabled, 1.0);
+            assert_eq!(new.range, 0.0);
+        }
+    }
+
+    #[test]
+    fn version_1_chunk_is_migrated() {
+        let v1 = StateV1 {
+            version: 1,
+            bands: vec![band_v1(1.0, 0.0, 3.0, 1.0), band_v1(0.0, -2.0, 1.0, 2.0)],
+        };
+        let data = serde_json::to_vec(&v1).unwrap();
+        let state = migrate(&data).unwrap();
+        assert_eq!(state, v1_to_v2(&v1));
+        assert_eq!(state.version, STATE_VERSION);
+    }
+
+    #[test]
+    fn current_chunk_is_unchanged() {
+        let state = EQState {
+            version: STATE_VERSION,
+            name: "Test".to_string(),
+            bands: vec![BandState::default(); 3],
+            globals: None,
+        };
+        assert_eq!(migrate(&state.to_bytes()).unwrap(), state);
+    }
+
+    #[test]
+    fn unknown_chunks_are_rejected() {
+        assert!(matches!(migrate(&[]), Err(StateError::Empty)));
+        assert!(matches!(
+            migrate(br#"{"version":99,"bands":[]}"#),
+            Err(StateError::UnsupportedVersion(99))
+        ));
+        // A block of floats, like a host's parameter dump, isn't a chunk
+        let floats: Vec<u8> = (0..24)
+            .flat_map(|_| 0.5f32.to_le_bytes().to_vec())
+            .collect();
+        assert!(matches!(migrate(&floats), Err(StateError::Malformed(_))));
+    }
+
+    /// What a host saved for the version before chunks, read with `get_parameter` after
+    /// that version's editor set: a Bell at 120 Hz +4.5 dB, a Linkwitz-Riley LowShelf at
+    /// 80 Hz -3 dB bw 0.7 slope 2, a HighShelf at 8 kHz +2 dB slope 3 in mode 2,
+    /// and an untouched fourth band
+    const BASELINE_DUMP: [f32; LEGACY_PARAMETER_COUNT] = [
+        0.0,
+        0.07074606,
+        0.59375,
+        0.037656903,
+        0.0,
+        0.0,
+        0.3,
+        0.05479966,
+        0.4375,
+        0.025104603,
+        0.06666667,
+        0.5,
+        0.4,
+        0.63198054,
+        0.5416667,
+        0.037656903,
+        0.13333334,
+        1.0,
+        0.0,
+        0.04904905,
+        0.5,
+        0.037656903,
+        0.0,
+    ];
+
+    /// A program file as hosts write it for a plugin without chunks
+    fn fxp(id: i32, name: &str, values: &[f32]) -> Vec<u8> {
+        let mut data = b"CcnK".to_vec();
+        data.extend(
+            ((FXP_HEADER - 8 + values.len() * 4) as i32)
+                .to_be_bytes()
+                .iter(),
+        );
+        data.extend(b"FxCk");
+        for x in [1, id, 1, values.len() as i32].iter() {
+            data.extend(x.to_be_bytes().iter());
+        }
+        let mut program_name = [0u8; 28];
+        program_name[..name.len()].copy_from_slice(name.as_bytes());
+        data.extend(program_name.iter());
+        for x in values.iter() {
+            data.extend(x.to_bits().to_be_bytes().iter());
+        }
+        data
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * 1e-6 + 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn baseline_parameters_keep_what_the_baseline_loaded() {
+        let state = migrate(&fxp(UNIQUE_ID, "Mix", &BASELINE_DUMP)).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.name, "Mix");
+        assert_eq!(state.bands.len(), LEGACY_BANDS);
+
+        // kind, freq, gain, bw, slope and mode as the baseline loaded them back,
+        // with mode 2 as Linkwitz-Riley at twice the gain
+        let expected = [
+            (BandKind::Bell, 120.0, 4.5, 1.0, 1.0, BandMode::Butterworth),
+            (
+                BandKind::LowShelf,
+                80.0,
+                -3.0,
+                0.7,
+                2.0,
+                BandMode::LinkwitzRiley,
+            ),
+            (
+                BandKind::HighShelf,
+                8000.0,
+                4.0,
+                1.0,
+                3.0,
+                BandMode::LinkwitzRiley,
+            ),
+            // Untouched parameters were saved unmapped, so the baseline loaded 68 Hz too
+            (
+                BandKind::Bell,
+                68.068069,
+                0.0,
+                1.0,
+                1.0,
+                BandMode::Butterworth,
+            ),
+        ];
+        for (band, (kind, freq, gain, bw, slope, mode)) in state.bands.iter().zip(expected.iter()) {
+            assert_eq!(band.kind, *kind as u8 as f64);
+            assert_close(band.freq, *freq);
+            assert_close(band.gain, *gain);
+            assert_close(band.bw, *bw);
+            assert_eq!(band.slope, *slope);
+            assert_eq!(band.mode, *mode as u8 as f64);
+            assert_eq!(band.enabled, 1.0);
+        }
+    }
+
+    #[test]
+    fn baseline_bank_loads_every_program() {
+        let mut data = b"CcnK".to_vec();
+        data.extend([0u8; 4].iter());
+        data.extend(b"FxBk");
+        for x in [1, UNIQUE_ID, 1, 2].iter() {
+            data.extend(x.to_be_bytes().iter());
+        }
+        data.extend([0u8; FXB_HEADER - 28].iter());
+        data.extend(fxp(UNIQUE_ID, "One", &BASELINE_DUMP));
+        data.extend(fxp(UNIQUE_ID, "Two", &[0.5; LEGACY_PARAMETER_COUNT]));
+
+        let bank = migrate_bank(&data).unwrap();
+        assert_eq!(bank.programs.len(), 2);
+        assert_eq!(
+            bank.programs[0],
+            migrate(&fxp(UNIQUE_ID, "One", &BASELINE_DUMP)).unwrap()
+        );
+        assert_eq!(bank.programs[1].name, "Two");
+        assert_close(bank.programs[1].bands[0].freq, 20.0 + 0.25 * 19980.0);
+    }
+
+    #[test]
+    fn program_files_of_other_plugins_are_rejected() {
+        assert!(matches!(
+            migrate(&fxp(1234, "Other", &BASELINE_DUMP)),
+            Err(StateError::Malformed(_))
+        ));
+        let truncated = fxp(UNIQUE_ID, "Short", &BASELINE_DUMP);
+        assert!(matches!(
+            migrate(&truncated[..truncated.len() - 2]),
+            Err(StateError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn single_program_bank_is_loaded_as_the_current_program() {
+        let v1 = StateV1 {
+            version: 1,
+            bands: vec![band_v1(0.0, 5.0, 1.0, 0.0)],
+        };
+        let bank = migrate_bank(&serde_json::to_vec(&v1).unwrap()).unwrap();
+        assert_eq!(bank.current_program, 0);
+        assert_eq!(bank.programs, vec![v1_to_v2(&v1)]);
+    }
+}
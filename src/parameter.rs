@@ -1,56 +1,157 @@
-use crate::{atomic_bool::AtomicBool, atomic_f64::AtomicF64, units::Units};
+use crate::{atomic_f64::AtomicF64, seqlock::SeqLock, units::Units};
+
+use std::sync::Arc;
+
+/// How the host's 0 to 1 normalized value is spread over `min..max`
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Mapping {
+    Linear,
+    /// Equal ratios take equal distance, the same curve as `map_to_freq`.
+    /// `min` has to be above 0.
+    Logarithmic,
+    /// Finer around the middle of the range, for dB centred on 0.
+    /// The skew is the power applied to the distance from the middle.
+    Bipolar(f64),
+}
+
+impl Mapping {
+    pub fn to_value(&self, x: f64, min: f64, max: f64) -> f64 {
+        match self {
+            Mapping::Linear => x.to_range(min, max),
+            Mapping::Logarithmic => min * (max / min).powf(x),
+            Mapping::Bipolar(skew) => {
+                let t = x * 2.0 - 1.0;
+                (t.signum() * t.abs().powf(*skew) * 0.5 + 0.5).to_range(min, max)
+            }
+        }
+    }
+
+    /// Exact inverse of `to_value`
+    pub fn to_normalized(&self, value: f64, min: f64, max: f64) -> f64 {
+        match self {
+            Mapping::Linear => value.from_range(min, max),
+            Mapping::Logarithmic => (value / min).ln() / (max / min).ln(),
+            Mapping::Bipolar(skew) => {
+                let t = value.from_range(min, max) * 2.0 - 1.0;
+                t.signum() * t.abs().powf(1.0 / skew) * 0.5 + 0.5
+            }
+        }
+    }
+}
 
 pub struct Parameter {
     name: String,
+    /// Unit shown next to the value by hosts. Empty when the display text has the unit,
+    /// or hosts show it twice.
+    label: String,
     normalized_value: AtomicF64,
     value: AtomicF64,
     pub default: f64,
     pub min: f64,
     pub max: f64,
     display_func: fn(f64) -> String,
-    pub transform_func: fn(f64) -> f64,
-    pub inv_transform_func: fn(f64) -> f64,
-    need_to_update_dsp: AtomicBool,
+    /// Reads text typed into the host, in the style of the display
+    parse_func: fn(&str) -> Option<f64>,
+    pub mapping: Mapping,
+    /// Labels of a stepped parameter, one per whole value from `min` to `max`.
+    /// Empty for continuous parameters.
+    choices: Vec<String>,
+    /// Shared by the parameters that are read together, like the fields of a band
+    lock: Arc<SeqLock>,
 }
 
 impl Parameter {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: &str,
+        label: &str,
         default: f64,
         min: f64,
         max: f64,
         display_func: fn(f64) -> String,
-        transform_func: fn(f64) -> f64,
-        inv_transform_func: fn(f64) -> f64,
+        parse_func: fn(&str) -> Option<f64>,
+        mapping: Mapping,
     ) -> Parameter {
         Parameter {
             name: String::from(name),
-            normalized_value: AtomicF64::new(default.from_range(min, max)),
+            label: String::from(label),
+            normalized_value: AtomicF64::new(mapping.to_normalized(default, min, max)),
             value: AtomicF64::new(default),
             default,
             min,
             max,
             display_func,
-            transform_func,
-            inv_transform_func,
-            need_to_update_dsp: AtomicBool::new(true),
+            parse_func,
+            mapping,
+            choices: Vec::new(),
+            lock: Arc::new(SeqLock::new()),
         }
     }
 
+    /// Makes writes to this parameter go through `lock`, shared with the rest of its group
+    pub fn with_lock(mut self, lock: &Arc<SeqLock>) -> Parameter {
+        self.lock = lock.clone();
+        self
+    }
+
+    /// A parameter that only takes the whole values `min`, `min + 1`, ... one per choice.
+    /// The normalized range is split evenly between the choices. Choice `k` of `n` is
+    /// stored as `k / (n - 1)` normalized, which falls inside its own part of the range.
+    pub fn new_stepped(
+        name: &str,
+        label: &str,
+        default: f64,
+        min: f64,
+        choices: Vec<String>,
+        parse_func: fn(&str) -> Option<f64>,
+    ) -> Parameter {
+        let max = min + (choices.len().max(2) - 1) as f64;
+        let mut parameter = Parameter::new(
+            name,
+            label,
+            default,
+            min,
+            max,
+            |x| format!("{}", x),
+            parse_func,
+            Mapping::Linear,
+        );
+        parameter.choices = choices;
+        parameter
+    }
+
+    pub fn is_stepped(&self) -> bool {
+        !self.choices.is_empty()
+    }
+
+    pub fn choices(&self) -> &[String] {
+        &self.choices
+    }
+
     pub fn get_normalized(&self) -> f64 {
         self.normalized_value.get()
     }
 
     pub fn get_normalized_default(&self) -> f64 {
-        (self.inv_transform_func)(self.default.from_range(self.min, self.max))
+        self.mapping.to_normalized(self.default, self.min, self.max)
     }
 
     pub fn set_normalized(&self, x: f64) {
-        self.need_to_update_dsp.set(true);
         let x = x.max(0.0).min(1.0);
-        self.normalized_value.set(x);
-        self.value
-            .set((self.transform_func)(x).to_range(self.min, self.max));
+        if self.is_stepped() {
+            let step_count = (self.max - self.min) + 1.0;
+            self.set(self.min + (x * step_count).floor().min(step_count - 1.0));
+            return;
+        }
+        self.lock.write(|| {
+            self.normalized_value.set(x);
+            self.value.set(
+                self.mapping
+                    .to_value(x, self.min, self.max)
+                    .max(self.min)
+                    .min(self.max),
+            );
+        });
     }
 
     pub fn get(&self) -> f64 {
@@ -58,24 +159,196 @@ impl Parameter {
     }
 
     pub fn set(&self, x: f64) {
-        self.need_to_update_dsp.set(true);
+        self.lock.write(|| self.set_unlocked(x));
+    }
+
+    /// Only for use inside a `write` of this parameter's lock,
+    /// to change several parameters of the group at once
+    pub fn set_unlocked(&self, x: f64) {
+        let x = if self.is_stepped() { x.round() } else { x };
         let x = x.max(self.min).min(self.max);
         self.value.set(x);
         self.normalized_value
-            .set((self.inv_transform_func)(x.from_range(self.min, self.max)));
+            .set(self.mapping.to_normalized(x, self.min, self.max));
     }
 
     pub fn get_display(&self) -> String {
-        (self.display_func)(self.value.get())
+        match self.choices.get((self.value.get() - self.min) as usize) {
+            Some(choice) => choice.clone(),
+            None => (self.display_func)(self.value.get()),
+        }
+    }
+
+    /// Returns false if the text couldn't be read, leaving the value as it was
+    pub fn set_from_text(&self, text: &str) -> bool {
+        match (self.parse_func)(text) {
+            Some(x) if x.is_finite() => {
+                self.set(x);
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn get_name(&self) -> String {
         self.name.clone()
     }
 
-    pub fn dsp_update(&self) -> bool {
-        let need_to_update = self.need_to_update_dsp.get();
-        self.need_to_update_dsp.set(false);
-        need_to_update
+    pub fn get_label(&self) -> String {
+        self.label.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stepped(count: usize) -> Parameter {
+        Parameter::new_stepped(
+            "Stepped",
+            "",
+            0.0,
+            0.0,
+            (0..count).map(|i| i.to_string()).collect(),
+            |text| text.parse().ok(),
+        )
+    }
+
+    #[test]
+    fn stepped_normalized_selects_the_choice_it_stores() {
+        for count in 2..=16 {
+            let parameter = stepped(count);
+            for k in 0..count {
+                parameter.set(k as f64);
+                let x = parameter.get_normalized();
+                assert_eq!(x, k as f64 / (count - 1) as f64);
+                parameter.set(0.0);
+                parameter.set_normalized(x);
+                assert_eq!(parameter.get(), k as f64);
+            }
+        }
+    }
+
+    #[test]
+    fn stepped_normalized_is_split_evenly() {
+        for count in 2..=16 {
+            let parameter = stepped(count);
+            let width = 1.0 / count as f64;
+            for k in 0..count {
+                let start = k as f64 * width;
+                for x in [
+                    start + width * 0.001,
+                    start + width * 0.5,
+                    start + width * 0.999,
+                ]
+                .iter()
+                {
+                    parameter.set_normalized(*x);
+                    assert_eq!(parameter.get(), k as f64, "{} of {} choices", x, count);
+                    assert_eq!(parameter.get_display(), k.to_string());
+                }
+            }
+            parameter.set_normalized(1.0);
+            assert_eq!(parameter.get(), (count - 1) as f64);
+        }
+    }
+
+    const MAPPINGS: [(Mapping, f64, f64); 4] = [
+        (Mapping::Linear, -60.0, 0.0),
+        (Mapping::Logarithmic, 20.0, 20000.0),
+        (Mapping::Bipolar(2.0), -24.0, 24.0),
+        (Mapping::Bipolar(3.0), -12.0, 24.0),
+    ];
+
+    fn continuous(mapping: Mapping, min: f64, max: f64) -> Parameter {
+        Parameter::new(
+            "Continuous",
+            "",
+            min,
+            min,
+            max,
+            |x| x.to_string(),
+            |text| text.parse().ok(),
+            mapping,
+        )
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() <= 1e-9 * b.abs().max(1.0), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn mappings_round_trip() {
+        for (mapping, min, max) in MAPPINGS.iter() {
+            let parameter = continuous(*mapping, *min, *max);
+            for i in 0..=100 {
+                let x = i as f64 / 100.0;
+                parameter.set_normalized(x);
+                let value = parameter.get();
+                assert_close(parameter.get_normalized(), x);
+
+                parameter.set_normalized(parameter.get_normalized());
+                assert_close(parameter.get(), value);
+                parameter.set(parameter.get());
+                assert_close(parameter.get_normalized(), x);
+                assert_close(mapping.to_normalized(value, *min, *max), x);
+            }
+        }
+    }
+
+    #[test]
+    fn mappings_reach_the_endpoints() {
+        for (mapping, min, max) in MAPPINGS.iter() {
+            let parameter = continuous(*mapping, *min, *max);
+            parameter.set_normalized(0.0);
+            assert_close(parameter.get(), *min);
+            parameter.set_normalized(1.0);
+            assert_close(parameter.get(), *max);
+            parameter.set(*min);
+            assert_close(parameter.get_normalized(), 0.0);
+            parameter.set(*max);
+            assert_close(parameter.get_normalized(), 1.0);
+            // Out of range values are clamped
+            parameter.set(*max * 2.0);
+            assert_close(parameter.get(), *max);
+            parameter.set_normalized(-1.0);
+            assert_close(parameter.get(), *min);
+        }
+    }
+
+    #[test]
+    fn bipolar_centre_is_the_middle_of_the_range() {
+        let parameter = continuous(Mapping::Bipolar(2.0), -24.0, 24.0);
+        parameter.set_normalized(0.5);
+        assert_eq!(parameter.get(), 0.0);
+        parameter.set(0.0);
+        assert_eq!(parameter.get_normalized(), 0.5);
+        // Finer around the centre than a linear mapping
+        parameter.set_normalized(0.75);
+        assert_close(parameter.get(), 6.0);
+    }
+
+    #[test]
+    fn logarithmic_matches_the_graph() {
+        let parameter = continuous(Mapping::Logarithmic, 20.0, 20000.0);
+        parameter.set_normalized(0.5);
+        assert_close(parameter.get(), (20.0f64 * 20000.0).sqrt());
+    }
+
+    #[test]
+    fn every_parameter_round_trips() {
+        let params = crate::eq_effect_parameters::EQEffectParameters::default();
+        for parameter in (0..params.len()).filter_map(|i| params.get(i)) {
+            for x in [0.0, 0.1, 0.25, 0.5, 0.8, 1.0].iter() {
+                parameter.set_normalized(*x);
+                let (value, normalized) = (parameter.get(), parameter.get_normalized());
+                parameter.set_normalized(normalized);
+                assert_close(parameter.get(), value);
+                assert_close(parameter.get_normalized(), normalized);
+                parameter.set(value);
+                assert_close(parameter.get(), value);
+                assert_close(parameter.get_normalized(), normalized);
+            }
+        }
     }
 }
@@ -0,0 +1,281 @@
+//! Host parameter list, generated from the band count.
+//!
+//! The list is made of the sections in `SECTIONS`, in the order they were added,
+//! so adding parameters never moves the ones hosts already know about.
+//! A section lists its band fields band by band, then its global fields.
+//! Every section has room for `MAX_FILTER_COUNT` bands, so no index depends on the
+//! band count. The slots of bands past the band count are left empty.
+//! The first section starts with the fields of the original hand written list,
+//! so the first 4 bands keep their indices.
+//! Each parameter also has an ID like `band3.freq` that doesn't depend on the order.
+
+use core::fmt;
+
+use crate::MAX_FILTER_COUNT;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BandField {
+    Kind,
+    Freq,
+    Gain,
+    Bw,
+    Slope,
+    Mode,
+    Enabled,
+    Channel,
+    Threshold,
+    Ratio,
+    Attack,
+    Release,
+    Range,
+    Key,
+}
+
+impl BandField {
+    pub fn id(&self) -> &'static str {
+        match self {
+            BandField::Kind => "kind",
+            BandField::Freq => "freq",
+            BandField::Gain => "gain",
+            BandField::Bw => "bw",
+            BandField::Slope => "slope",
+            BandField::Mode => "mode",
+            BandField::Enabled => "enabled",
+            BandField::Channel => "channel",
+            BandField::Threshold => "threshold",
+            BandField::Ratio => "ratio",
+            BandField::Attack => "attack",
+            BandField::Release => "release",
+            BandField::Range => "range",
+            BandField::Key => "key",
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum GlobalField {
+    Smoothing,
+    InputTrim,
+    OutputGain,
+    AutoGain,
+    LinearPhase,
+    FirLength,
+}
+
+impl GlobalField {
+    pub fn id(&self) -> &'static str {
+        match self {
+            GlobalField::Smoothing => "smoothing",
+            GlobalField::InputTrim => "input_trim",
+            GlobalField::OutputGain => "output_gain",
+            GlobalField::AutoGain => "auto_gain",
+            GlobalField::LinearPhase => "linear_phase",
+            GlobalField::FirLength => "fir_length",
+        }
+    }
+}
+
+/// Band fields and global fields added together. New fields go in a new section at the end.
+const SECTIONS: [(&[BandField], &[GlobalField]); 7] = [
+    (
+        &[
+            BandField::Kind,
+            BandField::Freq,
+            BandField::Gain,
+            BandField::Bw,
+            BandField::Slope,
+            BandField::Mode,
+        ],
+        &[GlobalField::Smoothing],
+    ),
+    (&[BandField::Enabled], &[]),
+    (
+        &[],
+        &[
+            GlobalField::InputTrim,
+            GlobalField::OutputGain,
+            GlobalField::AutoGain,
+        ],
+    ),
+    (&[BandField::Channel], &[]),
+    (
+        &[
+            BandField::Threshold,
+            BandField::Ratio,
+            BandField::Attack,
+            BandField::Release,
+            BandField::Range,
+        ],
+        &[],
+    ),
+    (&[BandField::Key], &[]),
+    (&[], &[GlobalField::LinearPhase, GlobalField::FirLength]),
+];
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ParameterId {
+    Band {
+        /// Zero based, the ID string is one based like the band names
+        band: usize,
+        field: BandField,
+    },
+    Global(GlobalField),
+}
+
+impl fmt::Display for ParameterId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParameterId::Band { band, field } => write!(f, "band{}.{}", band + 1, field.id()),
+            ParameterId::Global(field) => write!(f, "{}", field.id()),
+        }
+    }
+}
+
+pub struct ParameterRegistry {
+    /// `None` for the slots of bands past the band count
+    ids: Vec<Option<ParameterId>>,
+}
+
+impl ParameterRegistry {
+    pub fn new(band_count: usize) -> ParameterRegistry {
+        let mut ids = Vec::new();
+        for (band_fields, global_fields) in SECTIONS.iter() {
+            for band in 0..MAX_FILTER_COUNT {
+                ids.extend(band_fields.iter().map(|field| {
+                    if band < band_count {
+                        Some(ParameterId::Band {
+                            band,
+                            field: *field,
+                        })
+                    } else {
+                        None
+                    }
+                }));
+            }
+            ids.extend(
+                global_fields
+                    .iter()
+                    .map(|field| Some(ParameterId::Global(*field))),
+            );
+        }
+        ParameterRegistry { ids }
+    }
+
+    /// Number of indices, including empty slots. The same for every band count.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// `None` past the end and for empty slots
+    pub fn get(&self, index: usize) -> Option<ParameterId> {
+        self.ids.get(index).copied().flatten()
+    }
+
+    /// Every parameter in index order, skipping empty slots
+    pub fn ids(&self) -> impl Iterator<Item = ParameterId> + '_ {
+        self.ids.iter().filter_map(|id| *id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eq_effect_parameters::EQEffectParameters;
+    use crate::{MAX_FILTER_COUNT, MIN_FILTER_COUNT};
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn every_index_is_a_unique_parameter() {
+        for band_count in MIN_FILTER_COUNT..=MAX_FILTER_COUNT {
+            let params = EQEffectParameters::new(band_count);
+            let mut names = HashSet::new();
+            let mut ids = HashSet::new();
+            let mut parameters = HashSet::new();
+            let mut used = 0;
+            for i in 0..params.len() {
+                let id = match params.registry.get(i) {
+                    Some(id) => id,
+                    None => {
+                        assert!(params.get(i).is_none());
+                        continue;
+                    }
+                };
+                let parameter = params.get(i).unwrap();
+                assert!(ids.insert(id.to_string()), "{} is listed twice", id);
+                assert!(names.insert(parameter.get_name()), "{} has a used name", id);
+                assert!(parameters.insert(parameter as *const _), "{} is shared", id);
+                used += 1;
+            }
+            assert_eq!(used, params.registry.ids().count());
+            assert_eq!(used, band_count * 14 + 6);
+            assert!(params.registry.get(params.len()).is_none());
+        }
+    }
+
+    #[test]
+    fn indices_never_move() {
+        let band = |band, field| Some(ParameterId::Band { band, field });
+        let global = |field| Some(ParameterId::Global(field));
+        let fields = [
+            BandField::Kind,
+            BandField::Freq,
+            BandField::Gain,
+            BandField::Bw,
+            BandField::Slope,
+            BandField::Mode,
+        ];
+
+        for band_count in [8, 16, 24].iter() {
+            let registry = ParameterRegistry::new(*band_count);
+            let last = band_count - 1;
+            // The last band slot of a section is empty below 24 bands
+            let last_slot = |id| if *band_count == 24 { id } else { None };
+
+            // The original hand written list
+            for i in 0..24 {
+                assert_eq!(registry.get(i), band(i / 6, fields[i % 6]));
+            }
+            assert_eq!(registry.get(last * 6 + 5), band(last, BandField::Mode));
+            if *band_count < 24 {
+                assert_eq!(registry.get(band_count * 6), None);
+            }
+            assert_eq!(registry.get(143), last_slot(band(23, BandField::Mode)));
+            assert_eq!(registry.get(144), global(GlobalField::Smoothing));
+            assert_eq!(registry.get(145), band(0, BandField::Enabled));
+            assert_eq!(registry.get(145 + last), band(last, BandField::Enabled));
+            assert_eq!(registry.get(168), last_slot(band(23, BandField::Enabled)));
+            assert_eq!(registry.get(169), global(GlobalField::InputTrim));
+            assert_eq!(registry.get(170), global(GlobalField::OutputGain));
+            assert_eq!(registry.get(171), global(GlobalField::AutoGain));
+            assert_eq!(registry.get(172), band(0, BandField::Channel));
+            assert_eq!(registry.get(196), band(0, BandField::Threshold));
+            assert_eq!(registry.get(200), band(0, BandField::Range));
+            assert_eq!(
+                registry.get(196 + last * 5 + 4),
+                band(last, BandField::Range)
+            );
+            assert_eq!(registry.get(315), last_slot(band(23, BandField::Range)));
+            assert_eq!(registry.get(316), band(0, BandField::Key));
+            assert_eq!(registry.get(340), global(GlobalField::LinearPhase));
+            assert_eq!(registry.get(341), global(GlobalField::FirLength));
+            assert_eq!(registry.len(), 342);
+        }
+    }
+
+    #[test]
+    fn ids_match_the_names() {
+        assert_eq!(
+            ParameterId::Band {
+                band: 2,
+                field: BandField::Freq
+            }
+            .to_string(),
+            "band3.freq"
+        );
+        assert_eq!(
+            ParameterId::Global(GlobalField::FirLength).to_string(),
+            "fir_length"
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! Display text for parameter values, and parsers for values typed into the host.
+//!
+//! Parsers take the text in the same style as the display, with or without the unit.
+//! Stepped parameters are displayed with their list of choices.
+
+use crate::eq_effect_parameters::{AutoGain, BandChannel, BandKey, BandKind, BandMode};
+use crate::units::Units;
+use crate::FIR_LENGTHS;
+
+/// Lowercase, without the unit at either end or spaces around the number
+fn strip_unit(text: &str, units: &[&str]) -> String {
+    let mut text = text.trim().to_ascii_lowercase();
+    for unit in units.iter() {
+        if let Some(rest) = text.strip_suffix(unit) {
+            text = rest.trim_end().to_string();
+        }
+        if let Some(rest) = text.strip_prefix(unit) {
+            text = rest.trim_start().to_string();
+        }
+    }
+    text
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    text.parse::<f64>().ok().filter(|x| x.is_finite())
+}
+
+/// Names are matched ignoring case, spaces and dashes, so "low shelf" is LowShelf
+fn name_matches(name: &str, text: &str) -> bool {
+    let text = text
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>();
+    name.eq_ignore_ascii_case(&text)
+}
+
+pub fn display_freq(x: f64) -> String {
+    if x >= 1000.0 {
+        format!("{:.2} kHz", x / 1000.0)
+    } else if x >= 100.0 {
+        format!("{:.0} Hz", x)
+    } else {
+        format!("{:.1} Hz", x)
+    }
+}
+
+/// "1200", "1200 Hz", "1.2k" or "1.2 kHz"
+pub fn parse_freq(text: &str) -> Option<f64> {
+    let text = strip_unit(text, &["hz"]);
+    match text.strip_suffix('k') {
+        Some(khz) => parse_number(khz.trim_end()).map(|x| x * 1000.0),
+        None => parse_number(&text),
+    }
+}
+
+pub fn display_gain(x: f64) -> String {
+    format!("{:.1} dB", x)
+}
+
+/// "-3", "-3dB" or "-3.0 dB"
+pub fn parse_gain(text: &str) -> Option<f64> {
+    parse_number(&strip_unit(text, &["db"]))
+}
+
+/// Bandwidth is stored in octaves but shown as Q, like most EQs
+pub fn display_bw(x: f64) -> String {
+    format!("Q {:.2}", x.bw_to_q(0.0, 0.0))
+}
+
+/// "Q 2" or "2" as Q, "1.5 oct" as octaves
+pub fn parse_bw(text: &str) -> Option<f64> {
+    let lower = text.trim().to_ascii_lowercase();
+    if lower.ends_with("oct") || lower.ends_with("octaves") {
+        return parse_number(&strip_unit(&lower, &["octaves", "oct"])).filter(|x| *x > 0.0);
+    }
+    parse_number(&strip_unit(&lower, &["q"]))
+        .filter(|q| *q > 0.0)
+        .map(|q| q.q_to_bw(0.0, 0.0))
+}
+
+/// Slope 1 to `pole_count`, each one 6 dB/oct steeper
+pub fn slope_choices(pole_count: usize) -> Vec<String> {
+    (1..=pole_count)
+        .map(|x| format!("{} dB/oct", x * 6))
+        .collect()
+}
+
+/// "24 dB/oct" or "24", in steps of 6 dB/oct
+pub fn parse_slope(text: &str) -> Option<f64> {
+    parse_number(&strip_unit(text, &["db/oct", "db"]))
+        .map(|x| (x / 6.0).round())
+        .filter(|x| *x >= 1.0)
+}
+
+pub fn display_time(x: f64) -> String {
+    if x >= 1000.0 {
+        format!("{:.2} s", x / 1000.0)
+    } else if x >= 10.0 {
+        format!("{:.0} ms", x)
+    } else {
+        format!("{:.1} ms", x)
+    }
+}
+
+/// "20", "20 ms" or "0.5 s", in milliseconds
+pub fn parse_time(text: &str) -> Option<f64> {
+    let lower = text.trim().to_ascii_lowercase();
+    if lower.ends_with("ms") {
+        return parse_number(&strip_unit(&lower, &["ms"]));
+    }
+    if lower.ends_with('s') {
+        return parse_number(&strip_unit(&lower, &["s"])).map(|x| x * 1000.0);
+    }
+    parse_number(&lower)
+}
+
+pub fn switch_choices() -> Vec<String> {
+    vec!["Off".to_string(), "On".to_string()]
+}
+
+/// "On", "Off", "true", "false" or 0 and 1
+pub fn parse_switch(text: &str) -> Option<f64> {
+    match text.trim().to_ascii_lowercase().as_str() {
+        "on" | "true" | "yes" => Some(1.0),
+        "off" | "false" | "no" => Some(0.0),
+        text => parse_number(text),
+    }
+}
+
+pub fn display_ratio(x: f64) -> String {
+    format!("{:.1}:1", x)
+}
+
+/// "4", "4:1" or "4.0 : 1"
+pub fn parse_ratio(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let text = match text.strip_suffix('1') {
+        Some(rest) if rest.trim_end().ends_with(':') => rest.trim_end().trim_end_matches(':'),
+        _ => text,
+    };
+    parse_number(text.trim()).filter(|x| *x >= 1.0)
+}
+
+pub fn kind_choices() -> Vec<String> {
+    (0..BandKind::COUNT)
+        .map(|i| BandKind::from_u8(i as u8).to_string())
+        .collect()
+}
+
+/// A kind name like "LowShelf", or its number
+pub fn parse_kind(text: &str) -> Option<f64> {
+    kind_choices()
+        .iter()
+        .position(|name| name_matches(name, text))
+        .map(|i| i as f64)
+        .or_else(|| parse_number(text.trim()))
+}
+
+pub fn mode_choices() -> Vec<String> {
+    (0..BandMode::COUNT)
+        .map(|i| BandMode::from_u8(i as u8).to_string())
+        .collect()
+}
+
+/// A mode name like "Butterworth" or "Linkwitz-Riley", or its number
+pub fn parse_mode(text: &str) -> Option<f64> {
+    mode_choices()
+        .iter()
+        .position(|name| name_matches(name, text))
+        .map(|i| i as f64)
+        .or_else(|| parse_number(text.trim()))
+}
+
+pub fn channel_choices() -> Vec<String> {
+    (0..BandChannel::COUNT)
+        .map(|i| BandChannel::from_u8(i as u8).to_string())
+        .collect()
+}
+
+/// "Stereo", "Left", "Right", "Mid" or "Side", or its number
+pub fn parse_channel(text: &str) -> Option<f64> {
+    channel_choices()
+        .iter()
+        .position(|name| name_matches(name, text))
+        .map(|i| i as f64)
+        .or_else(|| parse_number(text.trim()))
+}
+
+pub fn key_choices() -> Vec<String> {
+    (0..BandKey::COUNT)
+        .map(|i| BandKey::from_u8(i as u8).to_string())
+        .collect()
+}
+
+/// "Internal" or "External", or its number
+pub fn parse_key(text: &str) -> Option<f64> {
+    key_choices()
+        .iter()
+        .position(|name| name_matches(name, text))
+        .map(|i| i as f64)
+        .or_else(|| parse_number(text.trim()))
+}
+
+pub fn fir_length_choices() -> Vec<String> {
+    FIR_LENGTHS.iter().map(|x| x.to_string()).collect()
+}
+
+/// A kernel length like "8192"
+pub fn parse_fir_length(text: &str) -> Option<f64> {
+    let length = parse_number(&strip_unit(text, &["taps"]))?;
+    FIR_LENGTHS
+        .iter()
+        .position(|x| *x as f64 == length)
+        .map(|i| i as f64)
+}
+
+pub fn auto_gain_choices() -> Vec<String> {
+    (0..AutoGain::COUNT)
+        .map(|i| AutoGain::from_u8(i as u8).to_string())
+        .collect()
+}
+
+/// "Off", "Curve" or "Live", or its number
+pub fn parse_auto_gain(text: &str) -> Option<f64> {
+    auto_gain_choices()
+        .iter()
+        .position(|name| name_matches(name, text))
+        .map(|i| i as f64)
+        .or_else(|| parse_number(text.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `parse(display(x))` gives `x` back, to within the precision of the display
+    fn assert_round_trips(
+        display: fn(f64) -> String,
+        parse: fn(&str) -> Option<f64>,
+        values: &[f64],
+        tolerance: f64,
+    ) {
+        for x in values.iter() {
+            let text = display(*x);
+            let parsed = parse(&text).unwrap_or_else(|| panic!("could not parse {:?}", text));
+            assert!(
+                (parsed - x).abs() <= tolerance * x.abs().max(1.0),
+                "{} was displayed as {:?} and parsed as {}",
+                x,
+                text,
+                parsed
+            );
+        }
+    }
+
+    /// Every choice parses to its own index, as does the index itself
+    fn assert_choices_round_trip(choices: Vec<String>, parse: fn(&str) -> Option<f64>) {
+        for (i, choice) in choices.iter().enumerate() {
+            assert_eq!(parse(choice), Some(i as f64), "{:?}", choice);
+            assert_eq!(parse(&i.to_string()), Some(i as f64));
+        }
+    }
+
+    #[test]
+    fn freq() {
+        assert_round_trips(
+            display_freq,
+            parse_freq,
+            &[
+                20.0, 55.5, 99.9, 100.0, 440.0, 999.0, 1000.0, 1250.0, 20000.0,
+            ],
+            0.005,
+        );
+        assert_eq!(parse_freq("1.2k"), Some(1200.0));
+        assert_eq!(parse_freq("1200 Hz"), Some(1200.0));
+        assert_eq!(parse_freq("1.2 kHz"), Some(1200.0));
+        assert_eq!(parse_freq("1200"), Some(1200.0));
+        assert_eq!(parse_freq("loud"), None);
+    }
+
+    #[test]
+    fn gain() {
+        assert_round_trips(
+            display_gain,
+            parse_gain,
+            &[-24.0, -3.5, 0.0, 0.1, 24.0],
+            0.0,
+        );
+        assert_eq!(parse_gain("-3dB"), Some(-3.0));
+        assert_eq!(parse_gain("-3.0 dB"), Some(-3.0));
+        assert_eq!(parse_gain("dB"), None);
+    }
+
+    #[test]
+    fn bw() {
+        let bws: Vec<f64> = [0.5, 0.71, 1.0, 2.0, 10.0]
+            .iter()
+            .map(|q| q.q_to_bw(0.0, 0.0))
+            .collect();
+        assert_round_trips(display_bw, parse_bw, &bws, 1e-9);
+        assert_eq!(display_bw(1.0), "Q 1.41");
+        assert_eq!(parse_bw("Q 2"), Some(2.0f64.q_to_bw(0.0, 0.0)));
+        assert_eq!(parse_bw("1.5 oct"), Some(1.5));
+        assert_eq!(parse_bw("Q 0"), None);
+        assert_eq!(parse_bw("-1 oct"), None);
+    }
+
+    #[test]
+    fn slope() {
+        let choices = slope_choices(16);
+        assert_eq!(choices.len(), 16);
+        for (i, choice) in choices.iter().enumerate() {
+            assert_eq!(parse_slope(choice), Some((i + 1) as f64), "{:?}", choice);
+        }
+        assert_eq!(parse_slope("24 dB/oct"), Some(4.0));
+        assert_eq!(parse_slope("24"), Some(4.0));
+        assert_eq!(parse_slope("0 dB/oct"), None);
+    }
+
+    #[test]
+    fn time() {
+        assert_round_trips(
+            display_time,
+            parse_time,
+            &[0.1, 5.5, 10.0, 20.0, 999.0, 1000.0, 1500.0, 5000.0],
+            0.005,
+        );
+        assert_eq!(parse_time("20 ms"), Some(20.0));
+        assert_eq!(parse_time("0.5 s"), Some(500.0));
+        assert_eq!(parse_time("20"), Some(20.0));
+    }
+
+    #[test]
+    fn ratio() {
+        assert_round_trips(display_ratio, parse_ratio, &[1.0, 1.5, 4.0, 20.0], 0.0);
+        assert_eq!(parse_ratio("4:1"), Some(4.0));
+        assert_eq!(parse_ratio("4.0 : 1"), Some(4.0));
+        assert_eq!(parse_ratio("4"), Some(4.0));
+        assert_eq!(parse_ratio("0.5"), None);
+    }
+
+    #[test]
+    fn choices() {
+        assert_choices_round_trip(kind_choices(), parse_kind);
+        assert_choices_round_trip(mode_choices(), parse_mode);
+        assert_choices_round_trip(channel_choices(), parse_channel);
+        assert_choices_round_trip(key_choices(), parse_key);
+        assert_choices_round_trip(auto_gain_choices(), parse_auto_gain);
+        assert_choices_round_trip(switch_choices(), parse_switch);
+        assert_eq!(
+            parse_kind("LowShelf"),
+            Some(BandKind::LowShelf as u8 as f64)
+        );
+        assert_eq!(
+            parse_kind("low shelf"),
+            Some(BandKind::LowShelf as u8 as f64)
+        );
+        assert_eq!(parse_mode("Linkwitz-Riley"), Some(1.0));
+        assert_eq!(parse_kind("shelf"), None);
+    }
+
+    #[test]
+    fn fir_length() {
+        for (i, choice) in fir_length_choices().iter().enumerate() {
+            assert_eq!(parse_fir_length(choice), Some(i as f64));
+        }
+        assert_eq!(parse_fir_length("16384 taps"), Some(2.0));
+        assert_eq!(parse_fir_length("1000"), None);
+    }
+}
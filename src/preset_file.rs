@@ -0,0 +1,364 @@
+//! Human readable preset files, so settings can be shared as text
+
+use serde::{Deserialize, Serialize};
+
+use crate::eq_effect_parameters::{BandChannel, BandKey, BandKind, BandMode, EQEffectParameters};
+use crate::state::{self, BandState, EQState, STATE_VERSION};
+
+use core::fmt;
+use std::path::{Path, PathBuf};
+
+const PRESET_FILE_VERSION: u32 = 1;
+const PRESET_EXTENSION: &str = "json";
+
+/// Plain values of a band, with the kind and mode written by name
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct PresetBand {
+    pub kind: BandKind,
+    pub freq: f64,
+    pub gain: f64,
+    pub bw: f64,
+    pub slope: u32,
+    pub mode: BandMode,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub channel: BandChannel,
+    #[serde(default = "state::default_threshold")]
+    pub threshold: f64,
+    #[serde(default = "state::default_ratio")]
+    pub ratio: f64,
+    #[serde(default = "state::default_attack")]
+    pub attack: f64,
+    #[serde(default = "state::default_release")]
+    pub release: f64,
+    #[serde(default)]
+    pub range: f64,
+    #[serde(default)]
+    pub key: BandKey,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct PresetFile {
+    pub version: u32,
+    pub bands: Vec<PresetBand>,
+}
+
+impl From<&BandState> for PresetBand {
+    fn from(state: &BandState) -> Self {
+        PresetBand {
+            kind: BandKind::from_u8(state.kind as u8),
+            freq: state.freq,
+            gain: state.gain,
+            bw: state.bw,
+            slope: state.slope as u32,
+            mode: BandMode::from_u8(state.mode as u8),
+            enabled: state.enabled >= 0.5,
+            channel: BandChannel::from_u8(state.channel as u8),
+            threshold: state.threshold,
+            ratio: state.ratio,
+            attack: state.attack,
+            release: state.release,
+            range: state.range,
+            key: BandKey::from_u8(state.key as u8),
+        }
+    }
+}
+
+impl From<&PresetBand> for BandState {
+    fn from(band: &PresetBand) -> Self {
+        BandState {
+            kind: band.kind as u8 as f64,
+            freq: band.freq,
+            gain: band.gain,
+            bw: band.bw,
+            slope: band.slope as f64,
+            mode: band.mode as u8 as f64,
+            enabled: if band.enabled { 1.0 } else { 0.0 },
+            channel: band.channel as u8 as f64,
+            threshold: band.threshold,
+            ratio: band.ratio,
+            attack: band.attack,
+            release: band.release,
+            range: band.range,
+            key: band.key as u8 as f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    NoPresetFolder,
+    InvalidName(String),
+    Io(std::io::Error),
+    Malformed(serde_json::Error),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PresetError::NoPresetFolder => write!(f, "could not find the preset folder"),
+            PresetError::InvalidName(name) => write!(f, "\"{}\" is not a valid preset name", name),
+            PresetError::Io(e) => write!(f, "{}", e),
+            PresetError::Malformed(e) => write!(f, "preset file is malformed: {}", e),
+            PresetError::UnsupportedVersion(v) => {
+                write!(f, "preset file version {} is not supported", v)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for PresetError {
+    fn from(e: std::io::Error) -> Self {
+        PresetError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for PresetError {
+    fn from(e: serde_json::Error) -> Self {
+        PresetError::Malformed(e)
+    }
+}
+
+impl PresetBand {
+    pub fn to_text(&self) -> Result<String, PresetError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_text(text: &str) -> Result<PresetBand, PresetError> {
+        Ok(serde_json::from_str(text)?)
+    }
+}
+
+impl PresetFile {
+    pub fn from_params(params: &EQEffectParameters) -> PresetFile {
+        PresetFile {
+            version: PRESET_FILE_VERSION,
+            bands: params
+                .get_state()
+                .bands
+                .iter()
+                .map(PresetBand::from)
+                .collect(),
+        }
+    }
+
+    pub fn apply(&self, params: &EQEffectParameters) {
+        params.set_state(&EQState {
+            version: STATE_VERSION,
+            name: String::new(),
+            bands: self.bands.iter().map(BandState::from).collect(),
+            globals: None,
+        });
+    }
+
+    pub fn to_text(&self) -> Result<String, PresetError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_text(text: &str) -> Result<PresetFile, PresetError> {
+        let preset: PresetFile = serde_json::from_str(text)?;
+        if preset.version != PRESET_FILE_VERSION {
+            return Err(PresetError::UnsupportedVersion(preset.version));
+        }
+        Ok(preset)
+    }
+}
+
+/// Per user folder the presets are saved to. It is only created by the first save.
+pub fn preset_folder() -> Result<PathBuf, PresetError> {
+    Ok(::dirs::data_dir()
+        .ok_or(PresetError::NoPresetFolder)?
+        .join("IMGUIBaseviewEQ")
+        .join("presets"))
+}
+
+fn preset_path(folder: &Path, name: &str) -> Result<PathBuf, PresetError> {
+    let valid = !name.trim().is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == ' ' || c == '-' || c == '_');
+    if !valid {
+        return Err(PresetError::InvalidName(name.to_string()));
+    }
+    Ok(folder.join(format!("{}.{}", name.trim(), PRESET_EXTENSION)))
+}
+
+/// Names of the presets in the preset folder, sorted
+pub fn list_presets() -> Result<Vec<String>, PresetError> {
+    list_presets_in(&preset_folder()?)
+}
+
+pub fn save_preset(params: &EQEffectParameters, name: &str) -> Result<(), PresetError> {
+    save_preset_in(&preset_folder()?, params, name)
+}
+
+/// The preset is fully parsed before anything is applied
+pub fn load_preset(params: &EQEffectParameters, name: &str) -> Result<(), PresetError> {
+    load_preset_from(&preset_folder()?, params, name)
+}
+
+/// A folder that doesn't exist yet has no presets
+fn list_presets_in(folder: &Path) -> Result<Vec<String>, PresetError> {
+    if !folder.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = ::std::fs::read_dir(folder)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .map_or(false, |ext| ext == PRESET_EXTENSION)
+        })
+        .filter_map(|path| Some(path.file_stem()?.to_string_lossy().to_string()))
+        .collect::<Vec<String>>();
+    names.sort();
+    Ok(names)
+}
+
+fn save_preset_in(
+    folder: &Path,
+    params: &EQEffectParameters,
+    name: &str,
+) -> Result<(), PresetError> {
+    let path = preset_path(folder, name)?;
+    let text = PresetFile::from_params(params).to_text()?;
+    ::std::fs::create_dir_all(folder)?;
+    ::std::fs::write(path, text)?;
+    Ok(())
+}
+
+fn load_preset_from(
+    folder: &Path,
+    params: &EQEffectParameters,
+    name: &str,
+) -> Result<(), PresetError> {
+    let text = ::std::fs::read_to_string(preset_path(folder, name)?)?;
+    PresetFile::from_text(&text)?.apply(params);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty folder of its own for each test, removed when the test ends
+    struct TempFolder(PathBuf);
+
+    impl TempFolder {
+        fn new(test: &str) -> TempFolder {
+            let folder = std::env::temp_dir().join(format!(
+                "IMGUIBaseviewEQ-{}-{}",
+                std::process::id(),
+                test
+            ));
+            let _ = std::fs::remove_dir_all(&folder);
+            TempFolder(folder)
+        }
+    }
+
+    impl Drop for TempFolder {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn edited_parameters() -> EQEffectParameters {
+        let params = EQEffectParameters::default();
+        params.bands[0].set_state(&BandState {
+            kind: BandKind::LowShelf as u8 as f64,
+            freq: 95.5,
+            gain: -4.25,
+            bw: 0.7,
+            slope: 3.0,
+            mode: BandMode::LinkwitzRiley as u8 as f64,
+            channel: BandChannel::Mid as u8 as f64,
+            range: 6.0,
+            ..BandState::default()
+        });
+        params.bands[3].set_state(&BandState {
+            freq: 4321.0,
+            gain: 2.5,
+            enabled: 0.0,
+            ..BandState::default()
+        });
+        params
+    }
+
+    #[test]
+    fn saved_preset_loads_and_is_listed() {
+        let folder = TempFolder::new("round_trip");
+        let params = edited_parameters();
+        save_preset_in(&folder.0, &params, "Vocal").unwrap();
+        save_preset_in(&folder.0, &EQEffectParameters::default(), "Flat").unwrap();
+        // Only preset files are listed
+        std::fs::write(folder.0.join("notes.txt"), "not a preset").unwrap();
+        assert_eq!(list_presets_in(&folder.0).unwrap(), vec!["Flat", "Vocal"]);
+
+        let loaded = EQEffectParameters::default();
+        load_preset_from(&folder.0, &loaded, "Vocal").unwrap();
+        assert_eq!(loaded.get_state().bands, params.get_state().bands);
+    }
+
+    #[test]
+    fn folder_is_created_by_the_first_save() {
+        let folder = TempFolder::new("first_save");
+        assert!(list_presets_in(&folder.0).unwrap().is_empty());
+        assert!(!folder.0.exists());
+        save_preset_in(&folder.0, &EQEffectParameters::default(), "First").unwrap();
+        assert!(folder.0.exists());
+    }
+
+    #[test]
+    fn bad_names_are_rejected() {
+        let folder = TempFolder::new("bad_names");
+        let params = EQEffectParameters::default();
+        for name in ["", "  ", "../escape", "a/b", "c:d", "e.json"].iter() {
+            assert!(matches!(
+                save_preset_in(&folder.0, &params, name),
+                Err(PresetError::InvalidName(_))
+            ));
+            assert!(matches!(
+                load_preset_from(&folder.0, &params, name),
+                Err(PresetError::InvalidName(_))
+            ));
+        }
+        assert!(!folder.0.exists());
+    }
+
+    #[test]
+    fn corrupt_preset_changes_nothing() {
+        let folder = TempFolder::new("corrupt");
+        let params = edited_parameters();
+        save_preset_in(&folder.0, &params, "Good").unwrap();
+        let path = preset_path(&folder.0, "Good").unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, &text[..text.len() / 2]).unwrap();
+        std::fs::write(
+            preset_path(&folder.0, "Future").unwrap(),
+            r#"{"version":99,"bands":[]}"#,
+        )
+        .unwrap();
+
+        let loaded = EQEffectParameters::default();
+        let before = loaded.get_state();
+        assert!(matches!(
+            load_preset_from(&folder.0, &loaded, "Good"),
+            Err(PresetError::Malformed(_))
+        ));
+        assert!(matches!(
+            load_preset_from(&folder.0, &loaded, "Future"),
+            Err(PresetError::UnsupportedVersion(99))
+        ));
+        assert!(matches!(
+            load_preset_from(&folder.0, &loaded, "Missing"),
+            Err(PresetError::Io(_))
+        ));
+        assert_eq!(loaded.get_state(), before);
+    }
+}
@@ -0,0 +1,200 @@
+//! Factory programs, exposed to the host as VST programs
+
+use crate::eq_effect_parameters::{BandKind, BandMode, EQEffectParameters};
+use crate::state::{BandState, BankState, EQState, ProgramState, STATE_VERSION};
+use std::sync::atomic::Ordering;
+
+fn band(kind: BandKind, freq: f64, gain: f64, bw: f64, slope: f64, mode: BandMode) -> BandState {
+    BandState {
+        kind: kind as u8 as f64,
+        freq,
+        gain,
+        bw,
+        slope,
+        mode: mode as u8 as f64,
+        ..BandState::default()
+    }
+}
+
+fn bell(freq: f64, gain: f64, bw: f64) -> BandState {
+    band(BandKind::Bell, freq, gain, bw, 1.0, BandMode::Butterworth)
+}
+
+/// Bands that aren't listed are left flat
+fn program(name: &str, band_count: usize, bands: &[BandState]) -> EQState {
+    EQState {
+        version: STATE_VERSION,
+        name: name.to_string(),
+        bands: (0..band_count)
+            .map(|i| bands.get(i).copied().unwrap_or_default())
+            .collect(),
+        globals: None,
+    }
+}
+
+/// The programs for an EQ with `band_count` bands
+pub fn factory_programs(band_count: usize) -> Vec<EQState> {
+    use BandKind::*;
+    use BandMode::*;
+    let program = |name, bands: &[BandState]| program(name, band_count, bands);
+    vec![
+        program("Init", &[]),
+        program(
+            "Vocal Presence",
+            &[
+                band(HighPass, 80.0, 0.0, 1.0, 2.0, Butterworth),
+                bell(300.0, -2.0, 1.5),
+                bell(3000.0, 3.0, 1.0),
+                band(HighShelf, 10000.0, 2.0, 1.0, 1.0, Butterworth),
+            ],
+        ),
+        program(
+            "Kick Tighten",
+            &[
+                band(HighPass, 30.0, 0.0, 1.0, 4.0, Butterworth),
+                bell(60.0, 3.0, 1.0),
+                bell(350.0, -4.0, 1.5),
+                bell(4000.0, 2.0, 1.0),
+            ],
+        ),
+        program(
+            "Rumble Cut",
+            &[band(HighPass, 40.0, 0.0, 1.0, 8.0, LinkwitzRiley)],
+        ),
+        program(
+            "Telephone",
+            &[
+                band(HighPass, 300.0, 0.0, 1.0, 4.0, Butterworth),
+                band(LowPass, 3400.0, 0.0, 1.0, 4.0, Butterworth),
+                bell(1500.0, 4.0, 1.0),
+            ],
+        ),
+        program("De-Mud", &[bell(250.0, -4.0, 1.5), bell(500.0, -2.0, 1.0)]),
+        program(
+            "Air",
+            &[band(HighShelf, 12000.0, 4.0, 1.0, 1.0, Butterworth)],
+        ),
+        program(
+            "Warmth",
+            &[
+                band(LowShelf, 150.0, 3.0, 1.0, 1.0, Butterworth),
+                bell(2500.0, -1.5, 1.0),
+            ],
+        ),
+    ]
+}
+
+impl EQEffectParameters {
+    pub fn program_count(&self) -> usize {
+        self.programs.lock().map_or(0, |programs| programs.len())
+    }
+
+    pub fn current_program(&self) -> usize {
+        self.current_program.load(Ordering::Relaxed)
+    }
+
+    pub fn get_program_name(&self, i: usize) -> String {
+        match self.programs.lock() {
+            Ok(programs) => programs
+                .get(i)
+                .map(|program| program.name.clone())
+                .unwrap_or_default(),
+            Err(_) => String::new(),
+        }
+    }
+
+    pub fn set_program_name(&self, name: String) {
+        if let Ok(mut programs) = self.programs.lock() {
+            if let Some(program) = programs.get_mut(self.current_program()) {
+                program.name = name;
+            }
+        }
+    }
+
+    /// Keeps the edits made to the current program, so they are there when switching back
+    pub fn store_current_program(&self) {
+        let mut state = self.get_state();
+        if let Ok(mut programs) = self.programs.lock() {
+            if let Some(program) = programs.get_mut(self.current_program()) {
+                state.name = program.name.clone();
+                *program = state;
+            }
+        }
+    }
+
+    pub fn change_program(&self, i: usize) {
+        if i >= self.program_count() || i == self.current_program() {
+            return;
+        }
+        self.store_current_program();
+        let state = match self.programs.lock() {
+            Ok(programs) => programs.get(i).cloned(),
+            Err(_) => None,
+        };
+        if let Some(state) = state {
+            self.current_program.store(i, Ordering::Relaxed);
+            self.set_state(&state);
+        }
+    }
+
+    pub fn get_bank_state(&self) -> BankState {
+        self.store_current_program();
+        BankState {
+            ab: self.get_ab_state(),
+            version: STATE_VERSION,
+            current_program: self.current_program(),
+            programs: self
+                .programs
+                .lock()
+                .map(|programs| programs.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Programs missing from the bank keep their current values,
+    /// as do the names of programs that don't have one
+    pub fn set_bank_state(&self, bank: &BankState) {
+        self.set_ab_state(&bank.ab);
+        let state = match self.programs.lock() {
+            Ok(mut programs) => {
+                for (program, new_program) in programs.iter_mut().zip(bank.programs.iter()) {
+                    let name = if new_program.name.is_empty() {
+                        program.name.clone()
+                    } else {
+                        new_program.name.clone()
+                    };
+                    *program = new_program.clone();
+                    program.name = name;
+                }
+                let current = bank.current_program.min(programs.len().saturating_sub(1));
+                self.current_program.store(current, Ordering::Relaxed);
+                programs.get(current).cloned()
+            }
+            Err(_) => None,
+        };
+        if let Some(state) = state {
+            self.set_state(&state);
+        }
+    }
+
+    /// A single program chunk replaces the current program, name included,
+    /// and the A/B slots if it has them
+    pub fn set_program_state(&self, state: &ProgramState) {
+        if let Some(ab) = &state.ab {
+            self.set_ab_state(ab);
+        }
+        if !state.program.name.is_empty() {
+            self.set_program_name(state.program.name.clone());
+        }
+        self.set_state(&state.program);
+    }
+
+    pub fn get_program_state(&self) -> ProgramState {
+        let mut program = self.get_state();
+        program.name = self.get_program_name(self.current_program());
+        ProgramState {
+            program,
+            ab: Some(self.get_ab_state()),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+
+/// Read attempts before giving up until the next call, so the audio thread never waits on a writer
+const READ_ATTEMPTS: usize = 4;
+/// Spins a writer waits for another writer before writing alongside it.
+/// Writers can run on the audio thread, so they never wait longer or yield.
+const WRITE_SPINS: usize = 64;
+
+/// Sequence lock for a group of atomics that have to be read together.
+///
+/// Writers step the sequence before and after they change values, and count
+/// themselves in `writers` while they do.
+/// Readers never block, they retry when a write was in progress or the
+/// sequence changed while they were reading.
+pub struct SeqLock {
+    sequence: AtomicUsize,
+    writers: AtomicUsize,
+}
+
+/// Last value read through a `SeqLock`, and the sequence it was read at
+#[derive(Clone, Copy)]
+pub struct Snapshot<T> {
+    sequence: usize,
+    pub value: T,
+}
+
+impl<T> Snapshot<T> {
+    /// The first `read_if_changed` always reads, since every write steps the sequence twice
+    pub fn new(value: T) -> Snapshot<T> {
+        Snapshot {
+            sequence: usize::MAX,
+            value,
+        }
+    }
+}
+
+impl SeqLock {
+    pub fn new() -> SeqLock {
+        SeqLock {
+            sequence: AtomicUsize::new(0),
+            writers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Writers wait for each other for at most `WRITE_SPINS` spins, in case the other
+    /// writer was preempted. Past that both write, and when they write the same
+    /// group each field keeps the value of whichever wrote it last.
+    pub fn write<R>(&self, write: impl FnOnce() -> R) -> R {
+        let mut spins = 0;
+        while self
+            .writers
+            .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spins += 1;
+            if spins == WRITE_SPINS {
+                self.writers.fetch_add(1, Ordering::Acquire);
+                break;
+            }
+            std::hint::spin_loop();
+        }
+        self.sequence.fetch_add(1, Ordering::AcqRel);
+        fence(Ordering::Release);
+        let result = write();
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.writers.fetch_sub(1, Ordering::Release);
+        result
+    }
+
+    /// Updates the snapshot if anything was written since it was taken.
+    /// Returns false if nothing changed, or if a write was in progress,
+    /// in which case the change is picked up by a later call.
+    pub fn read_if_changed<T>(&self, snapshot: &mut Snapshot<T>, read: impl Fn() -> T) -> bool {
+        for _ in 0..READ_ATTEMPTS {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before == snapshot.sequence {
+                return false;
+            }
+            if before % 2 == 1 || self.writers.load(Ordering::Acquire) != 0 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let value = read();
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                snapshot.value = value;
+                snapshot.sequence = before;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Default for SeqLock {
+    fn default() -> Self {
+        SeqLock::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eq_effect_parameters::EQEffectParameters;
+    use crate::state::BandState;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    const WRITES: usize = 1_000_000;
+
+    /// Every field is set from `n`, so a snapshot mixing two writes doesn't match either.
+    /// `n` can be read back from the freq.
+    fn band_state(n: usize) -> BandState {
+        let n = n % 1000;
+        let w = n as f64;
+        BandState {
+            kind: (n % 8) as f64,
+            freq: 20.0 + w,
+            gain: -24.0 + w * 0.048,
+            bw: 0.1 + w * 0.01,
+            slope: (1 + n % 16) as f64,
+            mode: (n % 2) as f64,
+            enabled: (n % 2) as f64,
+            channel: (n % 5) as f64,
+            threshold: -60.0 + w * 0.05,
+            ratio: (1 + n % 19) as f64,
+            attack: 0.1 + w * 0.1,
+            release: 5.0 + w,
+            range: 24.0 - w * 0.048,
+            key: (n % 2) as f64,
+        }
+    }
+
+    #[test]
+    fn snapshots_are_never_torn() {
+        let params = Arc::new(EQEffectParameters::default());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writer = {
+            let params = params.clone();
+            std::thread::spawn(move || {
+                for i in 0..WRITES {
+                    params.bands[0].set_state(&band_state(i));
+                }
+            })
+        };
+
+        let reader = {
+            let params = params.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let band = &params.bands[0];
+                let mut snapshot = Snapshot::new(band.get_state());
+                let mut reads = 0;
+                while !done.load(Ordering::Relaxed) {
+                    if band.update_snapshot(&mut snapshot) {
+                        let n = (snapshot.value.freq - 20.0).round() as usize;
+                        assert_eq!(snapshot.value, band_state(n), "torn snapshot");
+                        reads += 1;
+                    }
+                }
+                // The last write is never missed
+                band.update_snapshot(&mut snapshot);
+                assert_eq!(snapshot.value, band.get_state());
+                reads
+            })
+        };
+
+        writer.join().unwrap();
+        done.store(true, Ordering::Relaxed);
+        assert!(reader.join().unwrap() > 0);
+    }
+
+    #[test]
+    fn writers_never_wait_for_a_stalled_writer() {
+        let lock = Arc::new(SeqLock::new());
+        let value = Arc::new(AtomicUsize::new(0));
+        let stalled = Arc::new(AtomicBool::new(false));
+        let stall = Arc::new(AtomicBool::new(true));
+
+        let stalled_writer = {
+            let (lock, value) = (lock.clone(), value.clone());
+            let (stalled, stall) = (stalled.clone(), stall.clone());
+            std::thread::spawn(move || {
+                lock.write(|| {
+                    value.store(1, Ordering::Relaxed);
+                    stalled.store(true, Ordering::Release);
+                    while stall.load(Ordering::Acquire) {
+                        std::thread::yield_now();
+                    }
+                })
+            })
+        };
+        while !stalled.load(Ordering::Acquire) {
+            std::thread::yield_now();
+        }
+
+        // Returns while the other write is still in progress
+        lock.write(|| value.store(2, Ordering::Relaxed));
+        let mut snapshot = Snapshot::new(0);
+        let read = || value.load(Ordering::Relaxed);
+        assert!(!lock.read_if_changed(&mut snapshot, read));
+
+        stall.store(false, Ordering::Release);
+        stalled_writer.join().unwrap();
+        assert!(lock.read_if_changed(&mut snapshot, read));
+        assert_eq!(snapshot.value, 2);
+        assert!(!lock.read_if_changed(&mut snapshot, read));
+    }
+}
@@ -0,0 +1,300 @@
+//! Glides freq, gain and bandwidth to new values, each in the space it is heard in,
+//! so automation doesn't zipper. Kind, slope and mode change straight away.
+//! Switching a band or the whole EQ on and off crossfades instead.
+
+use crate::state::BandState;
+use crate::units::{Smooth, Units};
+
+/// Samples between coefficient updates, the DSP processes sub-blocks of this size
+pub const SMOOTHING_INTERVAL: usize = 32;
+
+/// Close enough to the target to stop updating coefficients
+const FREQ_TOLERANCE: f64 = 0.0001;
+const GAIN_TOLERANCE: f64 = 0.001;
+const BW_TOLERANCE: f64 = 0.0001;
+
+/// Seconds to fade a band or the whole EQ in or out
+const CROSSFADE_TIME: f64 = 0.005;
+
+fn retarget(smooth: &mut Smooth, target: f64, time: f64, jump: bool) {
+    smooth.attack = time;
+    if jump {
+        smooth.reset(target);
+    } else {
+        smooth.target = target;
+    }
+}
+
+pub struct BandSmoothing {
+    target: BandState,
+    /// Octaves, as log2 of Hz
+    freq: Smooth,
+    /// dB
+    gain: Smooth,
+    /// Octaves
+    bw: Smooth,
+}
+
+impl BandSmoothing {
+    pub fn new(state: &BandState) -> BandSmoothing {
+        BandSmoothing {
+            target: *state,
+            freq: Smooth::new(state.freq.log2()),
+            gain: Smooth::new(state.gain),
+            bw: Smooth::new(state.bw),
+        }
+    }
+
+    /// `time` is in seconds. A new kind, slope or mode also jumps the rest of
+    /// the band, since gliding from the old filter's values doesn't mean anything.
+    pub fn set_target(&mut self, state: &BandState, time: f64) {
+        let jump = state.kind != self.target.kind
+            || state.slope != self.target.slope
+            || state.mode != self.target.mode;
+        self.target = *state;
+        retarget(&mut self.freq, state.freq.log2(), time, jump);
+        retarget(&mut self.gain, state.gain, time, jump);
+        retarget(&mut self.bw, state.bw, time, jump);
+    }
+
+    /// Glides the gain to the band's gain plus `offset` dB, the gain a dynamic band adds.
+    /// `time` is in seconds.
+    pub fn set_gain_offset(&mut self, offset: f64, time: f64) {
+        retarget(&mut self.gain, self.target.gain + offset, time, false);
+    }
+
+    /// Values close to their target snap to it, so the last step lands exactly on the target
+    pub fn step(&mut self, samples: usize, sample_rate: f64) {
+        self.freq.step_by(samples, sample_rate);
+        self.gain.step_by(samples, sample_rate);
+        self.bw.step_by(samples, sample_rate);
+        self.freq.settle(FREQ_TOLERANCE);
+        self.gain.settle(GAIN_TOLERANCE);
+        self.bw.settle(BW_TOLERANCE);
+    }
+
+    pub fn is_settled(&self) -> bool {
+        [&self.freq, &self.gain, &self.bw]
+            .iter()
+            .all(|smooth| smooth.n == smooth.target)
+    }
+
+    /// The band as it should sound right now
+    pub fn current(&self) -> BandState {
+        BandState {
+            freq: self.freq.n.exp2(),
+            gain: self.gain.n,
+            bw: self.bw.n,
+            ..self.target
+        }
+    }
+}
+
+/// A gain that glides in dB like the band gains, applied as a linear gain
+pub struct GainSmoothing {
+    db: Smooth,
+}
+
+impl GainSmoothing {
+    pub fn new(db: f64) -> GainSmoothing {
+        GainSmoothing {
+            db: Smooth::new(db),
+        }
+    }
+
+    /// `time` is in seconds
+    pub fn set_target(&mut self, db: f64, time: f64) {
+        retarget(&mut self.db, db, time, false);
+    }
+
+    /// Moves the gain along by `samples`, returning the linear gain at the start and end of them
+    pub fn step(&mut self, samples: usize, sample_rate: f64) -> (f64, f64) {
+        let from = self.db.n.db_to_lin();
+        self.db.step_by(samples, sample_rate);
+        self.db.settle(GAIN_TOLERANCE);
+        (from, self.db.n.db_to_lin())
+    }
+}
+
+/// Linear fade between the dry signal (0) and the processed signal (1)
+pub struct Crossfade {
+    mix: f64,
+    target: f64,
+}
+
+impl Crossfade {
+    pub fn new(on: bool) -> Crossfade {
+        let mix = if on { 1.0 } else { 0.0 };
+        Crossfade { mix, target: mix }
+    }
+
+    pub fn set(&mut self, on: bool) {
+        self.target = if on { 1.0 } else { 0.0 };
+    }
+
+    /// Moves the fade along by `samples`, returning the mix at the start and end of them
+    pub fn step(&mut self, samples: usize, sample_rate: f64) -> (f64, f64) {
+        let from = self.mix;
+        let max_change = samples as f64 / (CROSSFADE_TIME * sample_rate);
+        self.mix = if self.target > from {
+            (from + max_change).min(self.target)
+        } else {
+            (from - max_change).max(self.target)
+        };
+        (from, self.mix)
+    }
+
+    /// Faded out and staying that way, so the processing can be skipped
+    pub fn is_off(&self) -> bool {
+        self.mix == 0.0 && self.target == 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    fn band(kind: f64, freq: f64, gain: f64, bw: f64) -> BandState {
+        BandState {
+            kind,
+            freq,
+            gain,
+            bw,
+            ..BandState::default()
+        }
+    }
+
+    /// Steps a sub-block at a time until settled, returning the number of sub-blocks
+    fn settle(smoothing: &mut BandSmoothing) -> usize {
+        let mut blocks = 0;
+        while !smoothing.is_settled() {
+            smoothing.step(SMOOTHING_INTERVAL, SAMPLE_RATE);
+            blocks += 1;
+            assert!(blocks < 10_000, "never settles");
+        }
+        blocks
+    }
+
+    #[test]
+    fn step_glides_and_lands_on_the_target() {
+        let mut smoothing = BandSmoothing::new(&band(0.0, 100.0, -6.0, 0.5));
+        let target = band(0.0, 10000.0, 6.0, 2.0);
+        smoothing.set_target(&target, 0.02);
+        assert!(!smoothing.is_settled());
+
+        smoothing.step(SMOOTHING_INTERVAL, SAMPLE_RATE);
+        let first = smoothing.current();
+        assert!(first.freq > 100.0 && first.freq < 10000.0);
+        assert!(first.gain > -6.0 && first.gain < 6.0);
+        assert!(first.bw > 0.5 && first.bw < 2.0);
+
+        assert!(settle(&mut smoothing) > 1);
+        let current = smoothing.current();
+        assert_eq!(current.gain, target.gain);
+        assert_eq!(current.bw, target.bw);
+        assert!((current.freq - target.freq).abs() < 1e-9);
+
+        // Once settled it stays exactly there
+        smoothing.step(SMOOTHING_INTERVAL, SAMPLE_RATE);
+        assert!(smoothing.is_settled());
+        assert_eq!(smoothing.current().gain, target.gain);
+    }
+
+    #[test]
+    fn kind_slope_and_mode_changes_jump() {
+        let start = band(0.0, 100.0, -6.0, 0.5);
+        let changes = [
+            BandState {
+                kind: 3.0,
+                ..band(0.0, 5000.0, 6.0, 2.0)
+            },
+            BandState {
+                slope: 4.0,
+                ..band(0.0, 5000.0, 6.0, 2.0)
+            },
+            BandState {
+                mode: 1.0,
+                ..band(0.0, 5000.0, 6.0, 2.0)
+            },
+        ];
+        for target in changes.iter() {
+            let mut smoothing = BandSmoothing::new(&start);
+            smoothing.set_target(target, 0.02);
+            assert!(smoothing.is_settled());
+            let current = smoothing.current();
+            assert_eq!(current.gain, target.gain);
+            assert_eq!(current.bw, target.bw);
+            assert!((current.freq - target.freq).abs() < 1e-9);
+            assert_eq!(
+                (current.kind, current.slope, current.mode),
+                (target.kind, target.slope, target.mode)
+            );
+        }
+    }
+
+    #[test]
+    fn gain_offset_glides_from_the_band_gain() {
+        let mut smoothing = BandSmoothing::new(&band(0.0, 1000.0, 3.0, 1.0));
+        smoothing.set_gain_offset(-6.0, 0.01);
+        smoothing.step(SMOOTHING_INTERVAL, SAMPLE_RATE);
+        assert!(smoothing.current().gain < 3.0 && smoothing.current().gain > -3.0);
+        settle(&mut smoothing);
+        assert_eq!(smoothing.current().gain, -3.0);
+    }
+
+    #[test]
+    fn gain_smoothing_returns_linear_gains() {
+        let mut gain = GainSmoothing::new(0.0);
+        assert_eq!(gain.step(SMOOTHING_INTERVAL, SAMPLE_RATE), (1.0, 1.0));
+        gain.set_target(-20.0, 0.01);
+        let (from, to) = gain.step(SMOOTHING_INTERVAL, SAMPLE_RATE);
+        assert_eq!(from, 1.0);
+        assert!(to < 1.0 && to > 0.1);
+        let mut last = to;
+        for _ in 0..1000 {
+            let (from, to) = gain.step(SMOOTHING_INTERVAL, SAMPLE_RATE);
+            assert_eq!(from, last);
+            last = to;
+        }
+        assert_eq!(last, (-20.0f64).db_to_lin());
+    }
+
+    #[test]
+    fn crossfade_takes_the_crossfade_time() {
+        let samples = (CROSSFADE_TIME * SAMPLE_RATE) as usize;
+        let mut fade = Crossfade::new(false);
+        assert!(fade.is_off());
+        fade.set(true);
+        assert!(!fade.is_off());
+
+        let mut done = 0;
+        let mut last = 0.0;
+        while done + SMOOTHING_INTERVAL < samples {
+            let (from, to) = fade.step(SMOOTHING_INTERVAL, SAMPLE_RATE);
+            done += SMOOTHING_INTERVAL;
+            assert_eq!(from, last);
+            assert!((to - done as f64 / samples as f64).abs() < 1e-9);
+            last = to;
+        }
+        assert!(last < 1.0);
+        assert_eq!(fade.step(SMOOTHING_INTERVAL, SAMPLE_RATE), (last, 1.0));
+        assert_eq!(fade.step(SMOOTHING_INTERVAL, SAMPLE_RATE), (1.0, 1.0));
+
+        fade.set(false);
+        assert!(!fade.is_off());
+        assert_eq!(fade.step(samples / 2, SAMPLE_RATE), (1.0, 0.5));
+        assert!(!fade.is_off());
+        assert_eq!(fade.step(samples / 2, SAMPLE_RATE), (0.5, 0.0));
+        assert!(fade.is_off());
+
+        // Turned back on before the fade out finished
+        let mut fade = Crossfade::new(true);
+        fade.set(false);
+        fade.step(samples / 2, SAMPLE_RATE);
+        fade.set(true);
+        assert_eq!(fade.step(samples, SAMPLE_RATE), (0.5, 1.0));
+        assert!(!fade.is_off());
+    }
+}
@@ -0,0 +1,109 @@
+//! Band solo, which plays the part of the input a band works on instead of the EQ.
+//!
+//! Bells, notches, band-passes and all-passes are heard through a band-pass at their
+//! frequency and bandwidth. Low shelves and high passes are heard through a low pass at
+//! their frequency, high shelves and low passes through a high pass, since that is the
+//! range they change. Bands on one channel are only heard on that channel.
+
+use audio_filters::filter_band::FilterBandCoefficients;
+use audio_filters::filter_band_wide::WideFilterBandCoefficients;
+use audio_filters::linkwitz_riley_wide::{WideLinkwitzRileyBand, WideLinkwitzRileyCoefficients};
+use wide::f64x4;
+
+use crate::eq_effect_parameters::{BandChannel, BandKind};
+use crate::smoothing::{Crossfade, SMOOTHING_INTERVAL};
+use crate::state::BandState;
+use crate::units::Units;
+use crate::{channel_routing, decode_mid_side, encode_mid_side};
+
+/// 12dB/oct, steep enough to hear the range without ringing
+const SOLO_SLOPE: f64 = 2.0;
+
+fn solo_coefficients(band: &BandState, sample_rate: f64) -> FilterBandCoefficients<f64> {
+    let butterworth_bw = std::f64::consts::FRAC_1_SQRT_2.q_to_bw(band.freq, 0.0);
+    match BandKind::from_u8(band.kind as u8) {
+        BandKind::LowShelf | BandKind::HighPass => {
+            FilterBandCoefficients::lowpass(band.freq, butterworth_bw, SOLO_SLOPE, sample_rate)
+        }
+        BandKind::HighShelf | BandKind::LowPass => {
+            FilterBandCoefficients::highpass(band.freq, butterworth_bw, SOLO_SLOPE, sample_rate)
+        }
+        BandKind::Bell | BandKind::Notch | BandKind::BandPass | BandKind::AllPass => {
+            FilterBandCoefficients::bandpass(band.freq, 0.0, band.bw, sample_rate)
+        }
+    }
+}
+
+pub struct Solo {
+    filter: WideLinkwitzRileyBand<f64x4>,
+    /// Kind, freq, bandwidth and sample rate the filter is set to
+    settings: (f64, f64, f64, f64),
+    mid_side: bool,
+    lanes: [f64; 2],
+    fade: Crossfade,
+}
+
+impl Solo {
+    pub fn new(sample_rate: f64) -> Solo {
+        let coeffs = FilterBandCoefficients::bandpass(1000.0, 0.0, 1.0, sample_rate);
+        let coeffs = WideFilterBandCoefficients::from(coeffs);
+        let coeffs = WideLinkwitzRileyCoefficients::from(coeffs);
+        Solo {
+            filter: WideLinkwitzRileyBand::from(&coeffs),
+            settings: (0.0, 1000.0, 1.0, sample_rate),
+            mid_side: false,
+            lanes: [1.0, 1.0],
+            fade: Crossfade::new(false),
+        }
+    }
+
+    /// Follows the soloed band, or fades out with `None`.
+    /// Only recalculates the filter when the band moved.
+    pub fn update(&mut self, band: Option<&BandState>, sample_rate: f64) {
+        self.fade.set(band.is_some());
+        let band = match band {
+            Some(band) => band,
+            None => return,
+        };
+        let (mid_side, lanes) = channel_routing(BandChannel::from_u8(band.channel as u8));
+        self.mid_side = mid_side;
+        self.lanes = lanes;
+        let settings = (band.kind, band.freq, band.bw, sample_rate);
+        if self.settings == settings {
+            return;
+        }
+        let coeffs = solo_coefficients(band, sample_rate);
+        let coeffs = WideFilterBandCoefficients::from(coeffs);
+        let coeffs = WideLinkwitzRileyCoefficients::from(coeffs);
+        self.filter.update(&coeffs);
+        self.settings = settings;
+    }
+
+    /// Crossfades `block` to `input` through the solo filter.
+    /// `input` is left/right, at most `SMOOTHING_INTERVAL` samples long.
+    pub fn process(&mut self, input: &[f64x4], block: &mut [f64x4], sample_rate: f64) {
+        let (from, to) = self.fade.step(block.len(), sample_rate);
+        if from == 0.0 && to == 0.0 {
+            return;
+        }
+        let mut solo = [f64x4::from([0.0; 4]); SMOOTHING_INTERVAL];
+        let solo = &mut solo[..block.len()];
+        solo.copy_from_slice(input);
+        if self.mid_side {
+            encode_mid_side(solo);
+        }
+        let lanes = f64x4::from([self.lanes[0], self.lanes[1], 0.0, 0.0]);
+        for audio in solo.iter_mut() {
+            let filtered: f64x4 =
+                (self.filter.filter1.process)(&mut self.filter.filter1, *audio).into();
+            *audio = filtered * lanes;
+        }
+        if self.mid_side {
+            decode_mid_side(solo);
+        }
+        let step = (to - from) / block.len() as f64;
+        for (i, (audio, solo)) in block.iter_mut().zip(solo.iter()).enumerate() {
+            *audio = *audio + (*solo - *audio) * (from + step * (i + 1) as f64);
+        }
+    }
+}
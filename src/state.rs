@@ -0,0 +1,557 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ab_comparison::ABComparison;
+use crate::eq_effect_parameters::{BandParameters, EQEffectParameters};
+use crate::migration;
+use crate::seqlock::Snapshot;
+
+use core::fmt;
+use std::sync::atomic::Ordering;
+
+/// Layout version written into every state chunk.
+/// Bump this whenever the meaning of the stored values changes,
+/// and add a step to `migration` that upgrades the previous version.
+pub const STATE_VERSION: u32 = 2;
+
+/// Plain (not normalized) values of every field of a band.
+/// `kind` and `mode` are the `BandKind` and `BandMode` as u8, `slope` is a whole number of poles.
+/// `enabled` is 1 or 0 and `channel` is the `BandChannel` as u8.
+/// `threshold` and `range` are in dB, `attack` and `release` in milliseconds.
+/// `key` is the `BandKey` as u8.
+/// Fields added after version 2 default to their parameter's default.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct BandState {
+    pub kind: f64,
+    pub freq: f64,
+    pub gain: f64,
+    pub bw: f64,
+    pub slope: f64,
+    pub mode: f64,
+    #[serde(default = "default_enabled")]
+    pub enabled: f64,
+    #[serde(default)]
+    pub channel: f64,
+    #[serde(default = "default_threshold")]
+    pub threshold: f64,
+    #[serde(default = "default_ratio")]
+    pub ratio: f64,
+    #[serde(default = "default_attack")]
+    pub attack: f64,
+    #[serde(default = "default_release")]
+    pub release: f64,
+    #[serde(default)]
+    pub range: f64,
+    #[serde(default)]
+    pub key: f64,
+}
+
+fn default_enabled() -> f64 {
+    1.0
+}
+
+pub fn default_threshold() -> f64 {
+    -20.0
+}
+
+pub fn default_ratio() -> f64 {
+    1.0
+}
+
+pub fn default_attack() -> f64 {
+    10.0
+}
+
+pub fn default_release() -> f64 {
+    100.0
+}
+
+impl BandState {
+    fn is_finite(&self) -> bool {
+        [
+            self.kind,
+            self.freq,
+            self.gain,
+            self.bw,
+            self.slope,
+            self.mode,
+            self.enabled,
+            self.channel,
+            self.threshold,
+            self.ratio,
+            self.attack,
+            self.release,
+            self.range,
+            self.key,
+        ]
+        .iter()
+        .all(|x| x.is_finite())
+    }
+}
+
+/// A flat bell, the same as a band that was just reset
+impl Default for BandState {
+    fn default() -> Self {
+        BandState {
+            kind: 0.0,
+            freq: 1000.0,
+            gain: 0.0,
+            bw: 1.0,
+            slope: 1.0,
+            mode: 0.0,
+            enabled: default_enabled(),
+            channel: 0.0,
+            threshold: default_threshold(),
+            ratio: default_ratio(),
+            attack: default_attack(),
+            release: default_release(),
+            range: 0.0,
+            key: 0.0,
+        }
+    }
+}
+
+/// Settings that aren't part of a band. `auto_gain` is the `AutoGain` as u8,
+/// `linear_phase` is 1 or 0 and `fir_length` is an index into `FIR_LENGTHS`.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct GlobalState {
+    pub smoothing: f64,
+    #[serde(default)]
+    pub input_trim: f64,
+    #[serde(default)]
+    pub output_gain: f64,
+    #[serde(default)]
+    pub auto_gain: f64,
+    #[serde(default)]
+    pub linear_phase: f64,
+    #[serde(default = "default_fir_length")]
+    pub fir_length: f64,
+}
+
+fn default_fir_length() -> f64 {
+    1.0
+}
+
+impl GlobalState {
+    fn is_finite(&self) -> bool {
+        [
+            self.smoothing,
+            self.input_trim,
+            self.output_gain,
+            self.auto_gain,
+            self.linear_phase,
+            self.fir_length,
+        ]
+        .iter()
+        .all(|x| x.is_finite())
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct EQState {
+    pub version: u32,
+    /// Program name, empty when the state isn't a program
+    #[serde(default)]
+    pub name: String,
+    pub bands: Vec<BandState>,
+    /// `None` for states that only hold bands, like preset files,
+    /// which leaves the current settings as they are
+    #[serde(default)]
+    pub globals: Option<GlobalState>,
+}
+
+/// The current program for the host's program chunk. The A/B slots are saved along with it,
+/// since some hosts only save program chunks.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct ProgramState {
+    #[serde(flatten)]
+    pub program: EQState,
+    /// `None` for chunks from before the slots were saved with the program
+    pub ab: Option<ABComparison>,
+}
+
+/// Every program, for the host's bank chunk
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct BankState {
+    pub version: u32,
+    pub current_program: usize,
+    pub programs: Vec<EQState>,
+    #[serde(default)]
+    pub ab: ABComparison,
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    Empty,
+    Malformed(serde_json::Error),
+    UnsupportedVersion(u32),
+    InvalidValue(usize),
+    InvalidGlobalValue,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Empty => write!(f, "state chunk is empty"),
+            StateError::Malformed(e) => write!(f, "state chunk is malformed: {}", e),
+            StateError::UnsupportedVersion(v) => write!(
+                f,
+                "state chunk version {} is not supported (current is {})",
+                v, STATE_VERSION
+            ),
+            StateError::InvalidValue(band) => {
+                write!(f, "state chunk has a non finite value in band {}", band)
+            }
+            StateError::InvalidGlobalValue => {
+                write!(f, "state chunk has a non finite global value")
+            }
+        }
+    }
+}
+
+impl EQState {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match serde_json::to_vec(self) {
+            Ok(data) => data,
+            Err(e) => {
+                ::log::error!("Could not serialize state: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Parses, migrates and validates a chunk. Nothing is applied to the parameters here,
+    /// so a corrupt or truncated chunk leaves the current state untouched.
+    pub fn from_bytes(data: &[u8]) -> Result<EQState, StateError> {
+        let state = migration::migrate(data)?;
+        state.validate()?;
+        Ok(state)
+    }
+
+    fn validate(&self) -> Result<(), StateError> {
+        if let Some(i) = self.bands.iter().position(|band| !band.is_finite()) {
+            return Err(StateError::InvalidValue(i));
+        }
+        match self.globals {
+            Some(globals) if !globals.is_finite() => Err(StateError::InvalidGlobalValue),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ABOnly {
+    #[serde(default)]
+    ab: Option<ABComparison>,
+}
+
+impl ProgramState {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match serde_json::to_vec(self) {
+            Ok(data) => data,
+            Err(e) => {
+                ::log::error!("Could not serialize program: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// The program goes through the same migration as any other state chunk
+    pub fn from_bytes(data: &[u8]) -> Result<ProgramState, StateError> {
+        let program = EQState::from_bytes(data)?;
+        let ab = serde_json::from_slice::<ABOnly>(data)
+            .ok()
+            .and_then(|chunk| chunk.ab);
+        Ok(ProgramState { program, ab })
+    }
+}
+
+impl BankState {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match serde_json::to_vec(self) {
+            Ok(data) => data,
+            Err(e) => {
+                ::log::error!("Could not serialize bank: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<BankState, StateError> {
+        let bank = migration::migrate_bank(data)?;
+        for program in bank.programs.iter() {
+            program.validate()?;
+        }
+        Ok(bank)
+    }
+}
+
+impl BandParameters {
+    pub fn get_state(&self) -> BandState {
+        BandState {
+            kind: self.get_kind() as u8 as f64,
+            freq: self.freq.get(),
+            gain: self.gain.get(),
+            bw: self.bw.get(),
+            slope: self.get_slope(),
+            mode: self.get_mode(),
+            enabled: self.enabled.get(),
+            channel: self.channel.get(),
+            threshold: self.threshold.get(),
+            ratio: self.ratio.get(),
+            attack: self.attack.get(),
+            release: self.release.get(),
+            range: self.range.get(),
+            key: self.key.get(),
+        }
+    }
+
+    /// Every field changes at once as far as the DSP can tell
+    pub fn set_state(&self, state: &BandState) {
+        self.lock.write(|| {
+            self.kind.set_unlocked(state.kind);
+            self.freq.set_unlocked(state.freq);
+            self.gain.set_unlocked(state.gain);
+            self.bw.set_unlocked(state.bw);
+            self.slope.set_unlocked(state.slope);
+            self.mode.set_unlocked(state.mode);
+            self.enabled.set_unlocked(state.enabled);
+            self.channel.set_unlocked(state.channel);
+            self.threshold.set_unlocked(state.threshold);
+            self.ratio.set_unlocked(state.ratio);
+            self.attack.set_unlocked(state.attack);
+            self.release.set_unlocked(state.release);
+            self.range.set_unlocked(state.range);
+            self.key.set_unlocked(state.key);
+        });
+    }
+
+    pub fn reset(&self) {
+        self.lock.write(|| {
+            for parameter in self.parameters().iter() {
+                parameter.set_unlocked(parameter.default);
+            }
+        });
+    }
+
+    /// Brings `snapshot` up to date if any field changed since it was taken.
+    /// Never blocks, a change that is being written is picked up by a later call.
+    pub fn update_snapshot(&self, snapshot: &mut Snapshot<BandState>) -> bool {
+        self.lock.read_if_changed(snapshot, || self.get_state())
+    }
+}
+
+impl EQEffectParameters {
+    pub fn get_state(&self) -> EQState {
+        EQState {
+            version: STATE_VERSION,
+            name: String::new(),
+            bands: self.bands.iter().map(|band| band.get_state()).collect(),
+            globals: Some(GlobalState {
+                smoothing: self.smoothing.get(),
+                input_trim: self.input_trim.get(),
+                output_gain: self.output_gain.get(),
+                auto_gain: self.auto_gain.get(),
+                linear_phase: self.linear_phase.get(),
+                fir_length: self.fir_length.get(),
+            }),
+        }
+    }
+
+    /// The DSP holds off on picking up changes until every band has been set,
+    /// including when the editor and the host set a state at the same time.
+    /// Bands missing from the state are reset, so they don't change the response.
+    pub fn set_state(&self, state: &EQState) {
+        self.applying_state.fetch_add(1, Ordering::AcqRel);
+        self.state_generation.fetch_add(1, Ordering::AcqRel);
+        if state.bands.len() > self.bands.len() {
+            ::log::warn!(
+                "State has {} bands, only the first {} are loaded",
+                state.bands.len(),
+                self.bands.len()
+            );
+        }
+        for (i, band) in self.bands.iter().enumerate() {
+            match state.bands.get(i) {
+                Some(band_state) => band.set_state(band_state),
+                None => band.reset(),
+            }
+        }
+        if let Some(globals) = state.globals {
+            self.smoothing.set(globals.smoothing);
+            self.input_trim.set(globals.input_trim);
+            self.output_gain.set(globals.output_gain);
+            self.auto_gain.set(globals.auto_gain);
+            self.linear_phase.set(globals.linear_phase);
+            self.fir_length.set(globals.fir_length);
+        }
+        self.applying_state.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    pub fn is_applying_state(&self) -> bool {
+        self.applying_state.load(Ordering::Acquire) > 0
+    }
+
+    /// `None` while a state is being applied. Reading the bands between two equal
+    /// generations means no state was applied in between, so they all come from one state.
+    pub fn state_generation(&self) -> Option<usize> {
+        if self.is_applying_state() {
+            return None;
+        }
+        let generation = self.state_generation.load(Ordering::Acquire);
+        if self.is_applying_state() {
+            None
+        } else {
+            Some(generation)
+        }
+    }
+
+    /// Loads a program chunk from the host, logging instead of applying anything if it is invalid
+    pub fn load_state_chunk(&self, data: &[u8]) {
+        match ProgramState::from_bytes(data) {
+            Ok(state) => self.set_program_state(&state),
+            Err(e) => ::log::error!("Could not load state: {}", e),
+        }
+    }
+
+    /// Loads a bank chunk from the host, logging instead of applying anything if it is invalid
+    pub fn load_bank_chunk(&self, data: &[u8]) {
+        match BankState::from_bytes(data) {
+            Ok(bank) => self.set_bank_state(&bank),
+            Err(e) => ::log::error!("Could not load bank: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MAX_FILTER_COUNT, MIN_FILTER_COUNT};
+
+    fn edited_parameters() -> EQEffectParameters {
+        let params = EQEffectParameters::default();
+        for (i, band) in params.bands.iter().enumerate() {
+            band.kind.set((i % 8) as f64);
+            band.freq.set(100.0 * (i + 1) as f64);
+            band.gain.set(-6.0 + i as f64);
+            band.bw.set(0.5 + 0.25 * i as f64);
+            band.slope.set(1.0 + i as f64);
+            band.mode.set((i % 2) as f64);
+            band.channel.set((i % 5) as f64);
+            band.range.set(-3.0);
+        }
+        params.smoothing.set(50.0);
+        params.output_gain.set(2.5);
+        params
+    }
+
+    #[test]
+    fn state_round_trips_through_a_chunk() {
+        let params = edited_parameters();
+        let data = params.get_state().to_bytes();
+
+        let loaded = EQEffectParameters::default();
+        loaded.load_state_chunk(&data);
+        assert_eq!(loaded.get_state(), params.get_state());
+        assert_ne!(
+            loaded.get_state(),
+            EQEffectParameters::default().get_state()
+        );
+    }
+
+    #[test]
+    fn truncated_chunk_is_rejected() {
+        let data = edited_parameters().get_state().to_bytes();
+        let truncated = &data[..data.len() / 2];
+        assert!(matches!(
+            EQState::from_bytes(truncated),
+            Err(StateError::Malformed(_))
+        ));
+        assert!(matches!(EQState::from_bytes(&[]), Err(StateError::Empty)));
+
+        let params = EQEffectParameters::default();
+        let before = params.get_state();
+        params.load_state_chunk(truncated);
+        assert_eq!(params.get_state(), before);
+    }
+
+    #[test]
+    fn non_finite_value_is_rejected() {
+        let mut state = edited_parameters().get_state();
+        state.bands[1].gain = f64::NAN;
+        assert!(matches!(state.validate(), Err(StateError::InvalidValue(1))));
+
+        let mut state = edited_parameters().get_state();
+        if let Some(globals) = state.globals.as_mut() {
+            globals.smoothing = f64::INFINITY;
+        }
+        assert!(matches!(
+            state.validate(),
+            Err(StateError::InvalidGlobalValue)
+        ));
+
+        // Json has no infinity or NaN, so they never make it through a chunk
+        let params = EQEffectParameters::default();
+        let before = params.get_state();
+        params.load_state_chunk(&state.to_bytes());
+        assert_eq!(params.get_state(), before);
+    }
+
+    #[test]
+    fn program_chunk_keeps_both_ab_slots() {
+        let params = EQEffectParameters::default();
+        params.bands[0].gain.set(3.0);
+        params.toggle_ab();
+        params.bands[0].gain.set(-3.0);
+        let data = params.get_program_state().to_bytes();
+
+        let loaded = EQEffectParameters::default();
+        loaded.load_state_chunk(&data);
+        assert_eq!(loaded.bands[0].gain.get(), -3.0);
+        loaded.toggle_ab();
+        assert_eq!(loaded.bands[0].gain.get(), 3.0);
+        loaded.toggle_ab();
+        assert_eq!(loaded.bands[0].gain.get(), -3.0);
+    }
+
+    #[test]
+    fn missing_bands_are_reset() {
+        let params = edited_parameters();
+        let mut state = params.get_state();
+        state.bands.truncate(4);
+        let data = state.to_bytes();
+
+        let loaded = edited_parameters();
+        loaded.load_state_chunk(&data);
+        let loaded = loaded.get_state();
+        assert_eq!(loaded.bands.len(), params.bands.len());
+        assert_eq!(loaded.bands[..4], state.bands[..]);
+        for band in loaded.bands[4..].iter() {
+            assert_eq!(*band, BandState::default());
+        }
+    }
+
+    #[test]
+    fn every_set_state_is_a_new_generation() {
+        let params = EQEffectParameters::default();
+        let before = params.state_generation().unwrap();
+        params.set_state(&params.get_state());
+        assert_ne!(params.state_generation(), Some(before));
+        params.applying_state.fetch_add(1, Ordering::AcqRel);
+        assert_eq!(params.state_generation(), None);
+    }
+
+    #[test]
+    fn factory_programs_match_the_band_count() {
+        for band_count in [MIN_FILTER_COUNT, 16, MAX_FILTER_COUNT].iter() {
+            let params = EQEffectParameters::new(*band_count);
+            for i in 0..params.program_count() {
+                params.change_program(i);
+                assert_eq!(params.get_state().bands.len(), *band_count);
+            }
+            let programs = params.get_bank_state().programs;
+            assert!(programs
+                .iter()
+                .all(|program| program.bands.len() == *band_count));
+        }
+    }
+}
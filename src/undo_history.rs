@@ -0,0 +1,213 @@
+//! Undo and redo for edits made in the editor.
+//!
+//! Only edits from the editor are recorded, each one as the parameter's value
+//! before and after a whole gesture. Undoing only touches that parameter,
+//! so changes from host automation are left alone.
+
+use crate::eq_effect_parameters::EQEffectParameters;
+use crate::parameter::Parameter;
+use crate::parameter_registry::ParameterId;
+
+use std::collections::VecDeque;
+
+const UNDO_DEPTH: usize = 100;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ParameterEdit {
+    pub id: ParameterId,
+    pub before: f64,
+    pub after: f64,
+}
+
+pub struct UndoHistory {
+    undo: VecDeque<ParameterEdit>,
+    redo: Vec<ParameterEdit>,
+    /// Edit in progress, while a drag is active
+    gesture: Option<ParameterEdit>,
+}
+
+impl Default for UndoHistory {
+    fn default() -> Self {
+        UndoHistory {
+            undo: VecDeque::with_capacity(UNDO_DEPTH),
+            redo: Vec::new(),
+            gesture: None,
+        }
+    }
+}
+
+/// ID of a parameter, so edits don't hold on to the parameter itself
+fn find_parameter(params: &EQEffectParameters, parameter: &Parameter) -> Option<ParameterId> {
+    params
+        .registry
+        .ids()
+        .find(|id| std::ptr::eq(params.parameter(*id), parameter))
+}
+
+impl UndoHistory {
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    fn push_undo(&mut self, edit: ParameterEdit) {
+        if self.undo.len() == UNDO_DEPTH {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(edit);
+    }
+
+    pub fn push(&mut self, edit: ParameterEdit) {
+        if edit.before == edit.after {
+            return;
+        }
+        self.push_undo(edit);
+        self.redo.clear();
+    }
+
+    /// Records a change that happens all at once, like picking a kind from a popup
+    pub fn set(&mut self, params: &EQEffectParameters, parameter: &Parameter, value: f64) {
+        let before = parameter.get();
+        parameter.set(value);
+        if let Some(id) = find_parameter(params, parameter) {
+            self.push(ParameterEdit {
+                id,
+                before,
+                after: parameter.get(),
+            });
+        }
+    }
+
+    pub fn begin_gesture(&mut self, params: &EQEffectParameters, parameter: &Parameter) {
+        self.gesture = find_parameter(params, parameter).map(|id| ParameterEdit {
+            id,
+            before: parameter.get(),
+            after: parameter.get(),
+        });
+    }
+
+    pub fn end_gesture(&mut self, params: &EQEffectParameters) {
+        if let Some(mut edit) = self.gesture.take() {
+            edit.after = params.parameter(edit.id).get();
+            self.push(edit);
+        }
+    }
+
+    pub fn undo(&mut self, params: &EQEffectParameters) {
+        if let Some(edit) = self.undo.pop_back() {
+            params.parameter(edit.id).set(edit.before);
+            self.redo.push(edit);
+        }
+    }
+
+    pub fn redo(&mut self, params: &EQEffectParameters) {
+        if let Some(edit) = self.redo.pop() {
+            params.parameter(edit.id).set(edit.after);
+            self.push_undo(edit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gain(params: &EQEffectParameters) -> &Parameter {
+        &params.bands[0].gain
+    }
+
+    #[test]
+    fn undo_and_redo_step_through_edits() {
+        let params = EQEffectParameters::default();
+        let mut history = UndoHistory::default();
+        assert!(!history.can_undo());
+        history.set(&params, gain(&params), 3.0);
+        history.set(&params, gain(&params), 6.0);
+        assert!(history.can_undo());
+        assert!(!history.can_redo());
+
+        history.undo(&params);
+        assert_eq!(gain(&params).get(), 3.0);
+        history.undo(&params);
+        assert_eq!(gain(&params).get(), 0.0);
+        assert!(!history.can_undo());
+        history.redo(&params);
+        assert_eq!(gain(&params).get(), 3.0);
+        history.redo(&params);
+        assert_eq!(gain(&params).get(), 6.0);
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn new_edit_clears_redo() {
+        let params = EQEffectParameters::default();
+        let mut history = UndoHistory::default();
+        history.set(&params, gain(&params), 3.0);
+        history.undo(&params);
+        assert!(history.can_redo());
+        history.set(&params, &params.bands[1].freq, 500.0);
+        assert!(!history.can_redo());
+        // Setting the same value isn't an edit, and keeps what can be redone
+        history.undo(&params);
+        history.set(&params, gain(&params), 0.0);
+        assert!(history.can_redo());
+    }
+
+    #[test]
+    fn history_keeps_the_last_edits() {
+        let params = EQEffectParameters::default();
+        let mut history = UndoHistory::default();
+        for i in 1..=UNDO_DEPTH + 10 {
+            history.set(&params, gain(&params), i as f64 * 0.1);
+        }
+        let mut undone = 0;
+        while history.can_undo() {
+            history.undo(&params);
+            undone += 1;
+        }
+        assert_eq!(undone, UNDO_DEPTH);
+        assert!((gain(&params).get() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn drag_is_one_edit() {
+        let params = EQEffectParameters::default();
+        let mut history = UndoHistory::default();
+        history.begin_gesture(&params, gain(&params));
+        for i in 1..=20 {
+            gain(&params).set(i as f64 * 0.5);
+        }
+        history.end_gesture(&params);
+        history.begin_gesture(&params, gain(&params));
+        gain(&params).set(-2.0);
+        history.end_gesture(&params);
+
+        history.undo(&params);
+        assert_eq!(gain(&params).get(), 10.0);
+        history.undo(&params);
+        assert_eq!(gain(&params).get(), 0.0);
+        assert!(!history.can_undo());
+
+        // A drag that ends where it started isn't an edit
+        history.begin_gesture(&params, gain(&params));
+        gain(&params).set(4.0);
+        gain(&params).set(0.0);
+        history.end_gesture(&params);
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn undo_only_touches_the_edited_parameter() {
+        let params = EQEffectParameters::default();
+        let mut history = UndoHistory::default();
+        history.set(&params, gain(&params), 3.0);
+        // Host automation on another parameter
+        params.bands[0].freq.set(250.0);
+        history.undo(&params);
+        assert_eq!(gain(&params).get(), 0.0);
+        assert_eq!(params.bands[0].freq.get(), 250.0);
+    }
+}
@@ -12,6 +12,8 @@ pub trait Units<T> {
     fn lin_to_db(self) -> T;
     fn sign(self, b: T) -> T;
     fn bw_to_q(self, f0: T, fs: T) -> T;
+    /// Inverse of `bw_to_q`, Q to bandwidth in octaves
+    fn q_to_bw(self, f0: T, fs: T) -> T;
 }
 
 impl Units<f64> for f64 {
@@ -42,6 +44,9 @@ impl Units<f64> for f64 {
 
         1.0 / (2.0 * (LN_2_F64 / 2.0 * self).sinh())
     }
+    fn q_to_bw(self, _f0: f64, _fs: f64) -> f64 {
+        2.0 / LN_2_F64 * (1.0 / (2.0 * self)).asinh()
+    }
 }
 
 impl Units<f32> for f32 {
@@ -73,6 +78,9 @@ impl Units<f32> for f32 {
 
         1.0 / (2.0 * (LN_2_F32 / 2.0 * self).sinh())
     }
+    fn q_to_bw(self, _f0: f32, _fs: f32) -> f32 {
+        2.0 / LN_2_F32 * (1.0 / (2.0 * self)).asinh()
+    }
 }
 
 pub fn map_to_freq(n: f32) -> f32 {
@@ -123,6 +131,10 @@ impl VariableRingBuffer {
         self.size
     }
 
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
     pub fn resize(&mut self, new_size: usize) {
         self.size = new_size.min(self.buffer.len());
         self.position = 0;
@@ -133,7 +145,11 @@ impl VariableRingBuffer {
 }
 pub struct AccumulatingRMS {
     buffer: VariableRingBuffer,
+    /// Running sum of the window, which drifts as values are added and taken away
     rms: f32,
+    /// Sum of the values pushed since the window last wrapped, the exact sum
+    /// of the window by the time it wraps again
+    window_sum: f32,
 }
 
 impl AccumulatingRMS {
@@ -144,6 +160,7 @@ impl AccumulatingRMS {
                 rms_max_size_samp,
             ),
             rms: 0.0,
+            window_sum: 0.0,
         }
     }
     pub fn resize(&mut self, sample_rate: usize, rms_size_ms: f32) {
@@ -151,6 +168,7 @@ impl AccumulatingRMS {
         if new_size != self.buffer.size() {
             self.buffer.resize(new_size);
             self.rms = 0.0;
+            self.window_sum = 0.0;
         }
     }
     pub fn process(&mut self, value: f32) -> f32 {
@@ -159,7 +177,13 @@ impl AccumulatingRMS {
         //remove the oldest rms value, add new one
         self.rms += -self.buffer.oldest() + new_rms_sample;
         self.buffer.push(new_rms_sample);
-        (self.rms / self.buffer.size() as f32).sqrt()
+        self.window_sum += new_rms_sample;
+        // The running sum drifts, and can go below 0, so it is replaced every window
+        if self.buffer.position() == 0 {
+            self.rms = self.window_sum;
+            self.window_sum = 0.0;
+        }
+        (self.rms.max(0.0) / self.buffer.size() as f32).sqrt()
     }
 }
 
@@ -224,4 +248,47 @@ impl Smooth {
         let factor = 1.0 / (sample_rate * self.attack);
         self.n += factor * (self.target - self.n);
     }
+
+    /// Same as calling `step` `samples` times
+    pub fn step_by(&mut self, samples: usize, sample_rate: f64) {
+        let factor = (1.0 / (sample_rate * self.attack)).min(1.0);
+        self.n = self.target + (self.n - self.target) * (1.0 - factor).powi(samples as i32);
+    }
+
+    /// Jumps to the target once within `tolerance` of it
+    pub fn settle(&mut self, tolerance: f64) {
+        if (self.target - self.n).abs() <= tolerance {
+            self.n = self.target;
+        }
+    }
+
+    pub fn reset(&mut self, n: f64) {
+        self.target = n;
+        self.n = n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulating_rms_follows_the_window() {
+        let mut rms = AccumulatingRMS::new(1000, 100.0, 1000);
+        let mut level = 0.0;
+        for _ in 0..100 {
+            level = rms.process(0.5);
+        }
+        assert!((level - 0.5).abs() < 1e-6);
+        for _ in 0..50 {
+            level = rms.process(0.0);
+        }
+        // Half the window at 0.25 power
+        assert!((level - 0.125f32.sqrt()).abs() < 1e-6);
+        // Whatever the running sum drifted by is gone once the window is summed again
+        for _ in 0..50 {
+            level = rms.process(0.0);
+        }
+        assert_eq!(level, 0.0);
+    }
 }